@@ -2,6 +2,7 @@ use std::mem;
 use std::ops::Range;
 
 use super::bitexact;
+use super::imdct15::IMDCT15;
 use crate::entropy::*;
 use crate::maths::*;
 use crate::packet::*;
@@ -14,11 +15,19 @@ const MAX_FRAME_SIZE: usize = SHORT_BLOCKSIZE * (1 << MAX_LOG_BLOCKS);
 const MAX_BANDS: usize = 21;
 const MIN_PERIOD: usize = 15;
 
+const EMPH_COEFF: f32 = 0.85000610;
+const ENERGY_SILENCE: f32 = -28.0;
+
 const SPREAD_NONE: usize = 0;
 const SPREAD_LIGHT: usize = 1;
 const SPREAD_NORMAL: usize = 2;
 const SPREAD_AGGRESSIVE: usize = 3;
 
+const LPC_ORDER: usize = 24;
+const PLC_PITCH_LAG_MAX: usize = 720;
+const PLC_PITCH_LAG_MIN: usize = 100;
+const PLC_MAX_PITCH_LOSSES: usize = 5;
+
 #[derive(Debug, Default)]
 struct PostFilter {
     period: usize,
@@ -30,16 +39,83 @@ struct PostFilter {
     gains_old: [f32; 3],
 }
 
+impl PostFilter {
+    /// Cross-fade from the old to the current filter over one overlap
+    /// using the squared window
+    fn apply_transition(&self, buf: &mut [f32], offset: usize) {
+        let t0 = self.period_old;
+        let t1 = self.period;
+        let [g00, g01, g02] = self.gains_old;
+        let [g10, g11, g12] = self.gains;
+
+        if g10 == 0.0 && g00 == 0.0 {
+            return;
+        }
+
+        let mut x1 = buf[offset + 1 - t1];
+        let mut x2 = buf[offset - t1];
+        let mut x3 = buf[offset - t1 - 1];
+        let mut x4 = buf[offset - t1 - 2];
+
+        for i in 0..OVERLAP {
+            let w = WINDOW[i] * WINDOW[i];
+            let o = offset + i;
+            let x0 = buf[o + 2 - t1];
+
+            buf[o] += (1.0 - w) * g00 * buf[o - t0]
+                + (1.0 - w) * g01 * (buf[o - t0 - 1] + buf[o + 1 - t0])
+                + (1.0 - w) * g02 * (buf[o - t0 - 2] + buf[o + 2 - t0])
+                + w * g10 * x2
+                + w * g11 * (x1 + x3)
+                + w * g12 * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+    }
+
+    /// Apply the current filter to `len` samples
+    fn apply(&self, buf: &mut [f32], offset: usize, len: usize) {
+        let t = self.period;
+        let [g0, g1, g2] = self.gains;
+
+        let mut x1 = buf[offset + 1 - t];
+        let mut x2 = buf[offset - t];
+        let mut x3 = buf[offset - t - 1];
+        let mut x4 = buf[offset - t - 2];
+
+        for o in offset..offset + len {
+            let x0 = buf[o + 2 - t];
+
+            buf[o] += g0 * x2 + g1 * (x1 + x3) + g2 * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+    }
+
+    fn update(&mut self) {
+        self.period_old = self.period;
+        self.gains_old = self.gains;
+    }
+}
+
 #[derive(Debug)]
 struct CeltFrame {
     pf: PostFilter,
     energy: [f32; MAX_BANDS],
-    prev_energy: [f32; MAX_BANDS],
+    prev_energy: [[f32; MAX_BANDS]; 2],
     collapse_masks: [u8; MAX_BANDS],
 
     buf: Vec<f32>, // TODO: replace with an array once const-generics
 
     deemph_coeff: f32,
+
+    lpc: [f32; LPC_ORDER],
 }
 
 impl Default for CeltFrame {
@@ -47,13 +123,144 @@ impl Default for CeltFrame {
         CeltFrame {
             pf: Default::default(),
             energy: Default::default(),
-            prev_energy: Default::default(),
+            prev_energy: [[ENERGY_SILENCE; MAX_BANDS]; 2],
             collapse_masks: Default::default(),
 
             buf: vec![0f32; 2048],
 
             deemph_coeff: 0f32,
+
+            lpc: Default::default(),
+        }
+    }
+}
+
+impl CeltFrame {
+    // See section 4.3.7.1
+    fn postfilter(&mut self, frame_size: usize) {
+        let pf = &mut self.pf;
+        let buf = &mut self.buf;
+
+        pf.apply_transition(buf, 1024);
+
+        pf.update();
+        pf.period = pf.period_new;
+        pf.gains = pf.gains_new;
+
+        if frame_size > OVERLAP {
+            pf.apply_transition(buf, 1024 + OVERLAP);
+
+            let len = frame_size - 2 * OVERLAP;
+            if pf.gains[0] > std::f32::EPSILON && len > 0 {
+                pf.apply(buf, 1024 + 2 * OVERLAP, len);
+            }
+
+            pf.update();
+        }
+    }
+
+    // Extend the history by frame_size samples repeating the last pitch
+    // period of the LPC residual, see celt_decode_lost() in libopus
+    fn extrapolate(&mut self, pitch: usize, fade: f32, frame_size: usize, first: bool) {
+        let mut exc = [0f32; LPC_ORDER + 1024];
+        let lpc = &mut self.lpc;
+        let buf = &mut self.buf;
+
+        exc[LPC_ORDER..].copy_from_slice(&buf[..1024]);
+
+        if first {
+            let mut ac = [0f32; LPC_ORDER + 1];
+
+            autocorr(&buf[..1024], &mut ac, true);
+            lag_window(&mut ac);
+            self::lpc(&ac, lpc);
+        }
+
+        // Whiten the last two pitch periods
+        let exc_len = (2 * pitch).min(1024);
+        for n in (LPC_ORDER + 1024 - exc_len..LPC_ORDER + 1024).rev() {
+            exc[n] += (0..LPC_ORDER).map(|k| lpc[k] * exc[n - 1 - k]).sum::<f32>();
+        }
+        let exc = &exc[LPC_ORDER..];
+
+        // Do not let the concealed signal grow if the energy is decaying
+        let decay_len = exc_len / 2;
+        let e1: f32 = 1.0 + exc[1024 - decay_len..].iter().map(|v| v * v).sum::<f32>();
+        let e2: f32 = 1.0
+            + exc[1024 - 2 * decay_len..1024 - decay_len]
+                .iter()
+                .map(|v| v * v)
+                .sum::<f32>();
+        let decay = (e1.min(e2) / e2).sqrt();
+
+        let len = frame_size + OVERLAP;
+        let offset = 1024 - pitch;
+        let s1: f32 = (0..len).map(|i| buf[offset + i % pitch].powi(2)).sum();
+
+        buf.copy_within(frame_size..1024, 0);
+
+        let start = 1024 - frame_size;
+        let mut attenuation = fade * decay;
+        for i in 0..len {
+            let j = i % pitch;
+            if i > 0 && j == 0 {
+                attenuation *= decay;
+            }
+            buf[start + i] = attenuation * exc[offset + j];
+        }
+
+        for n in start..start + len {
+            buf[n] -= (0..LPC_ORDER).map(|k| lpc[k] * buf[n - 1 - k]).sum::<f32>();
+        }
+
+        let s2: f32 = buf[start..start + len].iter().map(|v| v * v).sum();
+        if !(s1 > 0.2 * s2) {
+            buf[start..start + len].iter_mut().for_each(|v| *v = 0.0);
+        } else if s1 < s2 {
+            let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+            for (i, v) in buf[start..start + len].iter_mut().enumerate() {
+                *v *= if i < OVERLAP {
+                    1.0 - WINDOW[i] * (1.0 - ratio)
+                } else {
+                    ratio
+                };
+            }
+        }
+
+        // The next frame post-filters its first OVERLAP samples, undo it
+        // in advance on the part overlapping with it
+        let pf = &self.pf;
+        let t = pf.period;
+        let [g0, g1, g2] = pf.gains;
+        let mut etmp = [0f32; OVERLAP];
+        for (i, e) in etmp.iter_mut().enumerate() {
+            let o = 1024 + i;
+            *e = buf[o]
+                - g0 * buf[o - t]
+                - g1 * (buf[o - t - 1] + buf[o + 1 - t])
+                - g2 * (buf[o - t - 2] + buf[o + 2 - t]);
+        }
+
+        // Fold it as the IMDCT would for TDAC
+        for i in 0..OVERLAP / 2 {
+            buf[1024 + i] = WINDOW[i] * etmp[OVERLAP - 1 - i] + WINDOW[OVERLAP - 1 - i] * etmp[i];
+        }
+    }
+
+    fn deemphasis(&mut self, out: &mut [f32], frame_size: usize, stride: usize) {
+        let mut m = self.deemph_coeff;
+
+        for (o, &v) in out
+            .iter_mut()
+            .step_by(stride)
+            .zip(self.buf[1024 - frame_size..1024].iter())
+        {
+            let tmp = v + m;
+            m = tmp * EMPH_COEFF;
+            *o = tmp;
         }
+
+        self.deemph_coeff = m;
     }
 }
 
@@ -85,6 +292,11 @@ pub struct Celt {
 
     scratch: [f32; 22 * 8],
     seed: u32,
+
+    loss_count: usize,
+    pitch: usize,
+
+    imdct: Vec<IMDCT15>,
 }
 
 const POSTFILTER_TAPS: &[&[f32]] = &[
@@ -278,6 +490,31 @@ const STATIC_ALLOC: &[[u8; 21]; 11] = &[
     ],
 ];
 
+const MEAN_ENERGY: &[f32] = &[
+    6.437500, 6.250000, 5.750000, 5.312500, 5.062500, 4.812500, 4.500000, 4.375000, 4.875000,
+    4.687500, 4.562500, 4.437500, 4.875000, 4.625000, 4.312500, 4.500000, 4.375000, 4.625000,
+    4.750000, 4.437500, 3.750000,
+];
+
+const WINDOW: &[f32] = &[
+    0.000067286965, 0.00060551346, 0.001681597, 0.0032947962, 0.0054439944, 0.008127692,
+    0.011344002, 0.015090632, 0.019364886, 0.024163635, 0.029483315, 0.035319906, 0.04166891,
+    0.04852535, 0.055883717, 0.063737996, 0.07208162, 0.08090743, 0.0902077, 0.09997411,
+    0.110197686, 0.12086883, 0.13197729, 0.14351214, 0.15546177, 0.16781388, 0.1805555, 0.1936729,
+    0.20715171, 0.22097681, 0.23513243, 0.24960208, 0.2643686, 0.27941418, 0.2947204, 0.3102682,
+    0.32603788, 0.3420093, 0.35816178, 0.37447408, 0.39092463, 0.40749142, 0.42415214, 0.44088423,
+    0.45766485, 0.47447103, 0.49127978, 0.50806797, 0.52481264, 0.5414908, 0.5580797, 0.574557,
+    0.5909005, 0.6070884, 0.6230995, 0.63891304, 0.65450895, 0.66986775, 0.6849708, 0.6998001,
+    0.7143387, 0.7285705, 0.74248046, 0.7560542, 0.76927894, 0.7821426, 0.7946343, 0.80674446,
+    0.8184646, 0.8297873, 0.8407067, 0.8512178, 0.861317, 0.87100184, 0.88027114, 0.8891248,
+    0.897564, 0.90559095, 0.913209, 0.9204227, 0.9272374, 0.93365955, 0.93969655, 0.9453567,
+    0.9506491, 0.9555835, 0.9601707, 0.9644217, 0.9683485, 0.97196335, 0.97527903, 0.97830886,
+    0.98106617, 0.9835648, 0.9858187, 0.9878419, 0.9896486, 0.9912527, 0.9926685, 0.9939097,
+    0.99499005, 0.995923, 0.9967216, 0.99739873, 0.99796665, 0.9984373, 0.998822, 0.9991315,
+    0.99937606, 0.99956524, 0.999708, 0.9998125, 0.99988616, 0.9999356, 0.999967, 0.99998516,
+    0.9999946, 0.99999857, 0.9999998, 1.0,
+];
+
 const FREQ_BANDS: &[u8] = &[
     0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 34, 40, 48, 60, 78, 100,
 ];
@@ -492,9 +729,9 @@ fn interleave_hadamard(
             }
         }
     }
-    println!("interleave");
+    trace!("interleave");
     for v in &buf[..size] {
-        println!("  {:#.10}", v);
+        trace!("  {:#.10}", v);
     }
 
     buf[..size].copy_from_slice(&scratch[..size]);
@@ -509,9 +746,9 @@ fn deinterleave_hadamard(
 ) {
     let size = n0 * stride;
 
-    println!("before deinterleave");
+    trace!("before deinterleave");
     for v in &buf[..size] {
-        println!("  {:#.10}", v);
+        trace!("  {:#.10}", v);
     }
 
     if hadamard {
@@ -529,9 +766,9 @@ fn deinterleave_hadamard(
         }
     }
 
-    println!("deinterleave");
+    trace!("deinterleave");
     for v in &scratch[..size] {
-        println!("  {:#.10}", v);
+        trace!("  {:#.10}", v);
     }
 
     buf[..size].copy_from_slice(&scratch[..size]);
@@ -544,7 +781,7 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
     let mut y = y.iter_mut();
 
     fn update(k0: u32, k: u32, s: i32, norm: &mut u32) -> i32 {
-        println!("{} - {}", k0, k);
+        trace!("{} - {}", k0, k);
         let d = k0 - k;
 
         let val = (d as i32 + s) ^ s;
@@ -554,11 +791,11 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
 
     while n > 2 {
         let yy = y.next().unwrap();
-        println!("k {} n {} i {}", k, n, i);
+        trace!("k {} n {} i {}", k, n, i);
         if k >= n {
             let row = pvq_u_row(n as usize);
             let p = row[k as usize + 1] as u32;
-            println!("pulse {}", p);
+            trace!("pulse {}", p);
             let s = if i >= p {
                 i -= p;
                 -1
@@ -574,7 +811,7 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
                 loop {
                     k -= 1;
                     p = pvq_u_row(k as usize)[n as usize];
-                    println!("pulse {}", p);
+                    trace!("pulse {}", p);
                     if i >= p {
                         break;
                     }
@@ -590,13 +827,13 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
             }
 
             i -= p;
-            println!("-- i {} p {}", i, p);
+            trace!("-- i {} p {}", i, p);
             *yy = update(k0, k, s, &mut norm);
         } else {
             let mut p = pvq_u_row(k as usize)[n as usize] as u32;
             let q = pvq_u_row(k as usize + 1)[n as usize] as u32;
-            println!("p {} q {}", p, q);
-            if i > p && i < q {
+            trace!("p {} q {}", p, q);
+            if i >= p && i < q {
                 i -= p;
                 *yy = 0;
             } else {
@@ -617,7 +854,7 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
                 }
 
                 i -= p;
-                println!("i {} p {}", i, p);
+                trace!("i {} p {}", i, p);
                 *yy = update(k0, k, s, &mut norm);
             }
         }
@@ -626,7 +863,7 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
     {
         // n == 2
         let p = 2 * k + 1;
-        println!("p {} i {}", p, i);
+        trace!("p {} i {}", p, i);
         let s = if i >= p {
             i -= p;
             -1
@@ -641,7 +878,7 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
         }
 
         let yy = y.next().unwrap();
-        println!("n == 2");
+        trace!("n == 2");
         *yy = update(k0, k, s, &mut norm);
     }
 
@@ -650,11 +887,11 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
         let s = -(i as i32);
 
         let yy = y.next().unwrap();
-        println!("n == 1");
+        trace!("n == 1");
         *yy = update(k, 0, s, &mut norm);
     }
 
-    println!("norm {}", norm);
+    trace!("norm {}", norm);
     norm
 }
 
@@ -667,7 +904,7 @@ fn decode_pulses(rd: &mut RangeDecoder, y: &mut [i32], n: usize, k: usize) -> f3
     }
 
     let idx = rd.decode_uniform(pvq_v(n, k));
-    println!("idx {}", idx);
+    trace!("idx {}", idx);
     cwrsi(n as u32, k as u32, idx as u32, y) as f32
 }
 
@@ -682,11 +919,15 @@ fn exp_rotation1(x: &mut [f32], len: usize, stride: usize, c: f32, s: f32) {
         x[i] = c * x1 - s * x2;
     }
 
-    for i in (0..end - stride - 1).rev() {
+    if len < 2 * stride + 1 {
+        return;
+    }
+
+    for i in (0..len - 2 * stride).rev() {
         let x1 = x[i];
         let x2 = x[i + stride];
         x[i + stride] = c * x2 + s * x1;
-        x[0] = c * x1 - s * x2;
+        x[i] = c * x1 - s * x2;
     }
 }
 
@@ -710,7 +951,7 @@ fn exp_rotation(x: &mut [f32], len: usize, stride: usize, k: usize, spread: usiz
         }
     }
 
-    let _l = len / stride;
+    let len = len / stride;
     for i in 0..stride {
         if stride2 != 0 {
             exp_rotation1(&mut x[i * len..], len, stride2, s, c);
@@ -725,10 +966,10 @@ fn extract_collapse_mask(y: &[i32], b: usize) -> usize {
     }
 
     let mut collapse_mask = 0;
-    for block in y.chunks_exact(b) {
-        block.iter().enumerate().for_each(|(i, &v)| {
-            collapse_mask |= ((v != 0) as usize) << i;
-        });
+    for (i, block) in y.chunks_exact(y.len() / b).enumerate() {
+        if block.iter().any(|&v| v != 0) {
+            collapse_mask |= 1 << i;
+        }
     }
 
     return collapse_mask;
@@ -737,6 +978,7 @@ fn extract_collapse_mask(y: &[i32], b: usize) -> usize {
 fn bits2pulses(cache: &[u8], bits: i32) -> i32 {
     let mut low = 0;
     let mut high = cache[0] as usize;
+    let bits = bits - 1;
 
     for _i in 0..6 {
         let center = (low + high + 1) >> 1;
@@ -758,7 +1000,7 @@ fn pulses2bits(cache: &[u8], pulses: i32) -> i32 {
     if pulses == 0 {
         0
     } else {
-        cache[pulses as usize] as i32
+        cache[pulses as usize] as i32 + 1
     }
 }
 
@@ -785,7 +1027,7 @@ fn unquantize(
 }
 
 fn renormalize_vector(x: &mut [f32], gain: f32) {
-    let g: f32 = x.iter().map(|&v| v * v).sum();
+    let g: f32 = x.iter().fold(1e-15, |g, &v| g + v * v);
 
     let gain = gain / g.sqrt();
 
@@ -800,7 +1042,7 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
             (xp + xv * yv, side + yv * yv)
         });
 
-    println!("xp {} side {}", xp, side);
+    trace!("xp {} side {}", xp, side);
 
     let xp = xp * mid;
 
@@ -810,7 +1052,8 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
     let e1 = e + 2f32 * xp;
 
     if e0 < 6e-4f32 || e1 < 6e-4f32 {
-        &mut y[..n].copy_from_slice(&x[..n]);
+        y[..n].copy_from_slice(&x[..n]);
+        return;
     }
 
     let gain0 = 1f32 / e0.sqrt();
@@ -825,13 +1068,180 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
     }
 }
 
+fn denormalize(energy: &[f32], coeff: &mut [f32], band: Range<usize>, lm: usize) {
+    for i in band {
+        let start = (FREQ_BANDS[i] as usize) << lm;
+        let end = (FREQ_BANDS[i + 1] as usize) << lm;
+        let norm = (energy[i] + MEAN_ENERGY[i]).min(32.0).exp2();
+
+        coeff[start..end].iter_mut().for_each(|v| *v *= norm);
+    }
+}
+
+/// Cross-fade the interleaved `from` into `to` over 2.5ms using the
+/// squared CELT window, writing the result to `out`
+pub fn smooth_fade(out: &mut [f32], from: &[f32], to: &[f32], channels: usize) {
+    let len = OVERLAP * channels;
+
+    for (i, (o, (&a, &b))) in out[..len]
+        .iter_mut()
+        .zip(from.iter().zip(to.iter()))
+        .enumerate()
+    {
+        let w = WINDOW[i / channels] * WINDOW[i / channels];
+
+        *o = w * b + (1.0 - w) * a;
+    }
+}
+
+// Window the overlap between the previous frame tail in buf[..OVERLAP / 2]
+// and the beginning of the new IMDCT output in buf[OVERLAP / 2..OVERLAP]
+fn window_overlap(buf: &mut [f32]) {
+    for i in 0..OVERLAP / 2 {
+        let j = OVERLAP - 1 - i;
+        let s0 = buf[i];
+        let s1 = buf[j];
+        let wi = WINDOW[i];
+        let wj = WINDOW[j];
+
+        buf[i] = s0 * wj - s1 * wi;
+        buf[j] = s0 * wi + s1 * wj;
+    }
+}
+
+// Autocorrelation of `x`, windowing the first and the last OVERLAP
+// samples if `window` is set
+fn autocorr(x: &[f32], ac: &mut [f32], window: bool) {
+    let n = x.len();
+    let mut xx = [0f32; 1024];
+    let xx = &mut xx[..n];
+
+    xx.copy_from_slice(x);
+
+    if window {
+        for i in 0..OVERLAP {
+            xx[i] *= WINDOW[i];
+            xx[n - i - 1] *= WINDOW[i];
+        }
+    }
+
+    for (k, a) in ac.iter_mut().enumerate() {
+        *a = xx[k..].iter().zip(xx.iter()).map(|(&a, &b)| a * b).sum();
+    }
+}
+
+// Levinson-Durbin recursion, the resulting filter is 1 + sum(lpc[k] z^-(k+1))
+fn lpc(ac: &[f32], lpc: &mut [f32]) {
+    let mut error = ac[0];
+
+    lpc.iter_mut().for_each(|v| *v = 0.0);
+
+    if error <= 0.0 {
+        return;
+    }
+
+    for i in 0..lpc.len() {
+        let rr: f32 = ac[i + 1] + (0..i).map(|j| lpc[j] * ac[i - j]).sum::<f32>();
+        let r = -rr / error;
+
+        lpc[i] = r;
+        for j in 0..(i + 1) >> 1 {
+            let t0 = lpc[j];
+            let t1 = lpc[i - 1 - j];
+            lpc[j] = t0 + r * t1;
+            lpc[i - 1 - j] = t1 + r * t0;
+        }
+
+        error -= r * r * error;
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+// Noise floor and lag windowing before the lpc analysis
+fn lag_window(ac: &mut [f32]) {
+    ac[0] *= 1.0001;
+    for (i, a) in ac.iter_mut().enumerate().skip(1) {
+        let w = 0.008 * i as f32;
+        *a -= *a * w * w;
+    }
+}
+
+// Normalized cross-correlation between x and y, if positive
+fn pitch_score(x: &[f32], y: &[f32]) -> f32 {
+    let (xy, yy) = x
+        .iter()
+        .zip(y.iter())
+        .fold((0f32, 1f32), |(xy, yy), (&a, &b)| (xy + a * b, yy + b * b));
+
+    if xy > 0.0 {
+        xy * xy / yy
+    } else {
+        0.0
+    }
+}
+
+// Find the pitch period of the last 1024 samples of history, the search
+// is done on the whitened, 2x decimated signal and refined afterwards
+fn pitch_search(x: &[f32]) -> usize {
+    let mut lp = [0f32; 512];
+
+    lp[0] = 0.5 * x[0] + 0.25 * x[1];
+    for i in 1..512 {
+        lp[i] = 0.5 * x[2 * i] + 0.25 * (x[2 * i - 1] + x[2 * i + 1]);
+    }
+
+    let mut ac = [0f32; 5];
+    let mut a = [0f32; 4];
+    autocorr(&lp, &mut ac, false);
+    lag_window(&mut ac);
+    lpc(&ac, &mut a);
+
+    let mut g = 1.0;
+    for v in a.iter_mut() {
+        g *= 0.9;
+        *v *= g;
+    }
+
+    for n in (0..lp.len()).rev() {
+        lp[n] += (0..4)
+            .filter(|&k| n > k)
+            .map(|k| a[k] * lp[n - 1 - k])
+            .sum::<f32>();
+    }
+
+    let max = PLC_PITCH_LAG_MAX / 2;
+    let (_, coarse) = (PLC_PITCH_LAG_MIN / 2..=max)
+        .map(|t| {
+            let (xy, xx, yy) = lp[t..].iter().zip(lp.iter()).fold(
+                (0f32, 1f32, 1f32),
+                |(xy, xx, yy), (&a, &b)| (xy + a * b, xx + a * a, yy + b * b),
+            );
+            (xy / (xx * yy).sqrt(), t)
+        })
+        .fold((0.0, max), |best, cur| if cur.0 > best.0 { cur } else { best });
+
+    let len = x.len() - PLC_PITCH_LAG_MAX;
+    let lo = (2 * coarse - 1).max(PLC_PITCH_LAG_MIN);
+    let hi = (2 * coarse + 1).min(PLC_PITCH_LAG_MAX);
+    let (_, pitch) = (lo..=hi)
+        .map(|t| {
+            let y = &x[PLC_PITCH_LAG_MAX - t..PLC_PITCH_LAG_MAX - t + len];
+            (pitch_score(&x[PLC_PITCH_LAG_MAX..], y), t)
+        })
+        .fold((-1.0, 2 * coarse), |best, cur| if cur.0 > best.0 { cur } else { best });
+
+    pitch
+}
+
 struct BandInfo {
     itheta: usize,
     inv: bool,
     mid: f32,
     side: f32,
-    delta: i16,
-    qalloc: usize,
+    delta: i32,
+    qalloc: i32,
     fill: usize,
 }
 
@@ -860,6 +1270,9 @@ impl Celt {
             remaining2: 0,
             scratch: unsafe { mem::zeroed() },
             seed: 0,
+            loss_count: 0,
+            pitch: 0,
+            imdct: (0..4).map(|n| IMDCT15::new(n + 3)).collect(),
         }
     }
 
@@ -867,6 +1280,15 @@ impl Celt {
         self.stereo_pkt = pkt.stereo;
     }
 
+    /// Reset the decoder state, the next frame is decoded as a first one
+    pub fn flush(&mut self) {
+        self.frames = Default::default();
+        self.seed = 0;
+        self.loss_count = 0;
+        self.pitch = 0;
+        self.band = 0..MAX_BANDS;
+    }
+
     fn reset_gains(&mut self) {
         self.frames[0].pf.gains_new = [0.0; 3];
         self.frames[1].pf.gains_new = [0.0; 3];
@@ -885,7 +1307,7 @@ impl Celt {
                 0
             };
 
-            println!(
+            trace!(
                 "postfilter: octave {}, period {}, gain {}, tapset {}",
                 octave, period, gain, tapset
             );
@@ -895,12 +1317,12 @@ impl Celt {
                 frame.pf.gains_new = [taps[0] * gain, taps[1] * gain, taps[2] * gain];
             }
         } else {
-            println!("postfilter: no");
+            trace!("postfilter: no");
         }
     }
 
     fn decode_coarse_energy(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
-        let (alpha, beta, model) = if rd.available() > 3 && rd.decode_logp(3) {
+        let (alpha, beta, model) = if rd.available() >= 3 && rd.decode_logp(3) {
             (
                 0f32,
                 1f32 - 4915f32 / 32768f32,
@@ -914,7 +1336,7 @@ impl Celt {
             )
         };
 
-        println!("model {:.6} {:.6}", alpha, beta);
+        trace!("model {:.6} {:.6}", alpha, beta);
 
         let mut prev = [0f32; 2];
         let frames = &mut self.frames;
@@ -926,21 +1348,23 @@ impl Celt {
                     *en = 0.0
                 } else {
                     let available = rd.available();
-                    println!("available {}", available);
+                    trace!("available {}", available);
                     let value = if available >= 15 {
                         let k = i.min(20) << 1;
                         let v = rd
                             .decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6);
-                        println!("decode_laplace {:.6} <- {} {}", v, i, k);
+                        trace!("decode_laplace {:.6} <- {} {}", v, i, k);
                         v
-                    } else if available >= 1 {
+                    } else if available >= 2 {
                         let v = rd.decode_icdf(MODEL_ENERGY_SMALL) as isize;
                         (v >> 1) ^ -(v & 1)
+                    } else if available >= 1 {
+                        -(rd.decode_logp(1) as isize)
                     } else {
                         -1
                     } as f32;
 
-                    println!(
+                    trace!(
                         "energy {}/{} {:.6} * {:.6} + {:.6} + {:.6}",
                         i, j, *en, alpha, prev[j], value
                     );
@@ -968,7 +1392,7 @@ impl Celt {
                         let value = if available >= 15 {
                             let k = i.min(20) << 1;
                             let v = rd.decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6)
-                            println!("decode_laplace {} <- {} {}", v, i, k);
+                            trace!("decode_laplace {} <- {} {}", v, i, k);
                             v
                         } else if available >= 1 {
                             let v = rd.decode_icdf(MODEL_ENERGY_SMALL) as isize;
@@ -986,8 +1410,8 @@ impl Celt {
                     });
                 });
         */
-        println!("{:#.6?}", &frames[0].energy[..]);
-        println!("{:#.6?}", &frames[1].energy[..]);
+        trace!("{:#.6?}", &frames[0].energy[..]);
+        trace!("{:#.6?}", &frames[1].energy[..]);
     }
 
     fn decode_tf_changes(&mut self, rd: &mut RangeDecoder, band: Range<usize>, transient: bool) {
@@ -998,15 +1422,15 @@ impl Celt {
         let tf_select = TF_SELECT[self.lm][transient as usize];
 
         let select_bit = self.lm != 0 && available > bits.0;
-        println!("select_bit {} {}", select_bit, available);
+        trace!("select_bit {} {}", select_bit, available);
 
         let mut field_bits = bits.0;
         let mut diff = false;
         let mut changed = false;
         for (i, tf_change) in tf_changed[band.clone()].iter_mut().enumerate() {
-            if available > field_bits + select_bit as usize {
+            if available >= field_bits + select_bit as usize {
                 diff ^= rd.decode_logp(field_bits);
-                println!("band {} bits {} {}", i, field_bits, diff);
+                trace!("band {} bits {} {}", i, field_bits, diff);
                 available = rd.available();
                 changed |= diff;
             }
@@ -1028,7 +1452,7 @@ impl Celt {
                 *tf = tf_select[select as usize][changed as usize];
             }
         }
-        println!("tf_change {:#?}", &self.tf_change[band]);
+        trace!("tf_change {:#?}", &self.tf_change[band]);
     }
 
     fn decode_allocation(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
@@ -1039,7 +1463,7 @@ impl Celt {
         let scale = self.lm + self.stereo_pkt as usize;
         let mut skip_startband = band.start;
 
-        let _spread = if rd.available() > 4 {
+        self.spread = if rd.available() >= 4 {
             rd.decode_icdf(MODEL_SPREAD)
         } else {
             SPREAD_NORMAL
@@ -1053,16 +1477,16 @@ impl Celt {
                 *cap = (static_cap as i32 + 64) * (freq_range as i32) << scale >> 2;
             });
 
-        println!("caps {:#?}", &caps[..]);
+        trace!("caps {:#?}", &caps[..]);
 
         let mut dynalloc = 6;
         let mut boost_size = 0;
 
-        println!("consumed {}", rd.tell_frac());
+        trace!("consumed {}", rd.tell_frac());
 
         for i in band.clone() {
-            let quanta = FREQ_RANGE[i] << scale;
-            let quanta = (quanta << 3).min(quanta.max(6 << 3)) as i32;
+            let quanta = (FREQ_RANGE[i] as i32) << scale;
+            let quanta = (quanta << 3).min(quanta.max(6 << 3));
             let mut band_dynalloc = dynalloc;
             while (band_dynalloc << 3) + boost_size < rd.available_frac() && boost[i] < caps[i] {
                 let add = rd.decode_logp(band_dynalloc);
@@ -1079,15 +1503,15 @@ impl Celt {
             }
         }
 
-        let alloc_trim = if rd.available_frac() > boost_size + (6 << 3) {
+        let alloc_trim = if rd.available_frac() >= boost_size + (6 << 3) {
             rd.decode_icdf(ALLOC_TRIM)
         } else {
             5
         } as i32;
 
-        println!("alloc_trim {}", alloc_trim);
+        trace!("alloc_trim {}", alloc_trim);
 
-        let mut available = rd.available_frac() - 1;
+        let mut available = rd.available_frac().saturating_sub(1);
         self.anticollapse_bit =
             if self.blocks > 1 && self.lm >= 2 && available >= (self.lm + 2) << 3 {
                 available -= 1 << 3;
@@ -1096,7 +1520,7 @@ impl Celt {
                 0
             };
 
-        println!("anticollapse_bit {}", self.anticollapse_bit);
+        trace!("anticollapse_bit {}", self.anticollapse_bit);
 
         let skip_bit = if available >= 1 << 3 {
             available -= 1 << 3;
@@ -1105,7 +1529,7 @@ impl Celt {
             0
         };
 
-        println!("skip_bit {}", skip_bit);
+        trace!("skip_bit {}", skip_bit);
 
         let (mut intensity_stereo_bit, dual_stereo_bit) = if self.stereo_pkt {
             let intensity_stereo = LOG2_FRAC[band.end - band.start] as usize;
@@ -1125,24 +1549,24 @@ impl Celt {
             (0, 0)
         };
 
-        println!("intensity_stereo_bit {}", intensity_stereo_bit);
+        trace!("intensity_stereo_bit {}", intensity_stereo_bit);
 
         for i in band.clone() {
             let trim = alloc_trim - (5 + self.lm) as i32;
             let range = FREQ_RANGE[i] as i32 * (band.end - i - 1) as i32;
             let lm = self.lm + 3;
             let scale = lm as i32 + self.stereo_pkt as i32;
-            let stereo_threshold = (self.stereo_pkt as i32) << 8;
+            let stereo_threshold = (self.stereo_pkt as i32 + 1) << 3;
 
             threshold[i] = ((3 * FREQ_RANGE[i] as i32) << lm >> 4).max(stereo_threshold);
 
             trim_offset[i] = trim * (range << scale) >> 6;
 
-            if FREQ_RANGE[i] << self.lm == 1 {
+            if (FREQ_RANGE[i] as i32) << self.lm == 1 {
                 trim_offset[i] -= stereo_threshold;
             }
 
-            println!("trim_offset {} {}", i, trim_offset[i]);
+            trace!("trim_offset {} {}", i, trim_offset[i]);
         }
 
         const CELT_VECTOR: usize = 11;
@@ -1161,7 +1585,7 @@ impl Celt {
                     << self.lm
                     >> 2;
 
-                println!("bandbits {}", bandbits);
+                trace!("bandbits {}", bandbits);
 
                 let bandbits = if bandbits != 0 {
                     (bandbits + trim_offset[i]).max(0)
@@ -1178,7 +1602,7 @@ impl Celt {
                     }
                 }
 
-                println!("total {} {}", total, available);
+                trace!("total {} {}", total, available);
             }
 
             if total as usize > available {
@@ -1186,10 +1610,10 @@ impl Celt {
             } else {
                 low = center + 1;
             }
-            println!("{} {} {}", high, low, center);
+            trace!("{} {} {}", high, low, center);
         }
 
-        println!("high {} low {}", high, low);
+        trace!("high {} low {}", high, low);
 
         high = low;
         low -= 1;
@@ -1197,7 +1621,7 @@ impl Celt {
         let mut bits1 = [0; MAX_BANDS];
         let mut bits2 = [0; MAX_BANDS];
 
-        println!("high {} low {}", high, low);
+        trace!("high {} low {}", high, low);
 
         for i in band.clone() {
             let bits_estimation = |idx: usize| -> i32 {
@@ -1212,20 +1636,27 @@ impl Celt {
                 }
             };
             bits1[i] = bits_estimation(low);
-            bits2[i] = bits_estimation(high);
-
-            if boost[i] != 0 {
-                if low != 0 {
-                    bits1[i] += boost[i];
+            bits2[i] = if high >= CELT_VECTOR {
+                if caps[i] != 0 {
+                    (caps[i] + trim_offset[i]).max(0)
+                } else {
+                    0
                 }
+            } else {
+                bits_estimation(high)
+            };
 
-                bits2[i] += boost[i];
+            if low != 0 {
+                bits1[i] += boost[i];
+            }
+            bits2[i] += boost[i];
 
+            if boost[i] != 0 {
                 skip_startband = i;
             }
 
             bits2[i] = (bits2[i] - bits1[i]).max(0);
-            println!("bits2 {}", bits2[i]);
+            trace!("bits2 {}", bits2[i]);
         }
 
         const ALLOC_STEPS: usize = 6;
@@ -1276,7 +1707,7 @@ impl Celt {
             self.pulses[i] = bits;
             total += bits;
 
-            println!("total {}", total);
+            trace!("total {}", total);
         }
 
         let mut bands = band.clone().rev();
@@ -1285,7 +1716,7 @@ impl Celt {
             let j = bands.next().unwrap();
             let codedband = j + 1;
 
-            println!("codedband {} {}", codedband, j);
+            trace!("codedband {} {}", codedband, j);
             if j == skip_startband {
                 available += skip_bit;
                 break codedband;
@@ -1297,10 +1728,11 @@ impl Celt {
                 let bits = remaining / band_delta;
                 (bits, remaining - bits * band_delta)
             };
+            let skip_delta = (FREQ_BANDS[j] - FREQ_BANDS[band.start]) as i32;
             let mut allocation =
-                self.pulses[j] + bits * FREQ_BANDS[j] as i32 + 0.max(remaining - band_delta);
+                self.pulses[j] + bits * FREQ_RANGE[j] as i32 + 0.max(remaining - skip_delta);
 
-            if allocation >= threshold[j].max(coded_channel_bits) {
+            if allocation >= threshold[j].max(coded_channel_bits + (1 << 3)) {
                 if rd.decode_logp(1) {
                     break codedband;
                 }
@@ -1324,7 +1756,7 @@ impl Celt {
 
             total += self.pulses[j];
 
-            println!("band skip total {}", total);
+            trace!("band skip total {}", total);
         };
 
         self.intensity_stereo = if intensity_stereo_bit != 0 {
@@ -1342,7 +1774,7 @@ impl Celt {
             false
         };
 
-        println!(
+        trace!(
             "intensity {}, dual {}",
             self.intensity_stereo, self.dual_stereo as usize
         );
@@ -1354,7 +1786,7 @@ impl Celt {
             (bits, remaining - bits * band_delta)
         };
 
-        for i in band.clone() {
+        for i in band.start..codedband {
             let freq_range = FREQ_RANGE[i] as i32;
             let bits = remaining.min(freq_range);
 
@@ -1362,13 +1794,13 @@ impl Celt {
             remaining -= bits;
         }
 
-        println!("remaining {}", remaining);
+        trace!("remaining {}", remaining);
 
         let mut extrabits = 0;
 
         const FINE_OFFSET: i32 = 21;
 
-        for i in band.clone() {
+        for i in band.start..codedband {
             let n = (FREQ_RANGE[i] as i32) << self.lm;
             let prev_extra = extrabits;
             self.pulses[i] += extrabits;
@@ -1384,7 +1816,7 @@ impl Celt {
                 let dof_channels = dof * (LOG_FREQ_RANGE[i] as i32 + duration);
                 let mut offset = (dof_channels >> 1) - dof * FINE_OFFSET;
 
-                println!("dof {} {} {}", dof, dof_channels, offset);
+                trace!("dof {} {} {}", dof, dof_channels, offset);
 
                 if n == 2 {
                     offset += dof << 1;
@@ -1400,12 +1832,12 @@ impl Celt {
                 let pulse = self.pulses[i] + offset;
 
                 let fine_bits = (pulse + (dof << 2)) / (dof << 3);
-                println!("pulses {}, offset {}", self.pulses[i], offset);
+                trace!("pulses {}, offset {}", self.pulses[i], offset);
                 let max_bits = (self.pulses[i] >> 3) >> (self.stereo_pkt as usize);
                 let max_bits = max_bits.min(MAX_FINE_BITS).max(0);
 
                 self.fine_bits[i] = fine_bits.max(0).min(max_bits);
-                println!("fine_bits {} {}", fine_bits, self.fine_bits[i]);
+                trace!("fine_bits {} {}", fine_bits, self.fine_bits[i]);
                 self.fine_priority[i] = self.fine_bits[i] * (dof << 3) >= pulse;
 
                 self.pulses[i] -= self.fine_bits[i] << (self.stereo_pkt as usize) << 3;
@@ -1428,8 +1860,8 @@ impl Celt {
                 extrabits -= extra_fine;
             }
 
-            println!("extrabits {}", extrabits);
-            println!("fine_bits {}", self.fine_bits[i]);
+            trace!("extrabits {}", extrabits);
+            trace!("fine_bits {}", self.fine_bits[i]);
         }
 
         self.remaining = extrabits;
@@ -1439,7 +1871,7 @@ impl Celt {
             self.pulses[i] = 0;
             self.fine_priority[i] = self.fine_bits[i] < 1;
 
-            println!("fine_bits end {}", self.fine_bits[i]);
+            trace!("fine_bits end {}", self.fine_bits[i]);
         }
 
         self.codedband = codedband;
@@ -1454,9 +1886,9 @@ impl Celt {
             for f in 0..self.stereo_pkt as usize + 1 {
                 let frame = &mut self.frames[f];
                 let q2 = rd.rawbits(self.fine_bits[i] as usize) as f32;
-                println!("-- fine_bits {}", self.fine_bits[i]);
+                trace!("-- fine_bits {}", self.fine_bits[i]);
                 let offset = (q2 + 0.5) * (1 << (14 - self.fine_bits[i])) as f32 / 16384.0 - 0.5;
-                println!("q2 {}", q2);
+                trace!("q2 {}", q2);
                 frame.energy[i] += offset;
             }
         }
@@ -1497,19 +1929,19 @@ impl Celt {
         &self,
         rd: &mut RangeDecoder,
         band: usize,
-        lm: usize,
+        lm: isize,
         n: usize,
-        b: usize,
+        b: i32,
         b0: usize,
         blocks: usize,
         dualstereo: bool,
         fill: usize,
     ) -> BandInfo {
-        const QTHETA_OFFSET: usize = 4;
-        const QTHETA_OFFSET_TWOPHASE: usize = 16;
+        const QTHETA_OFFSET: i32 = 4;
+        const QTHETA_OFFSET_TWOPHASE: i32 = 16;
         const BITRES: i32 = 2 << 3;
-        println!("band {}", band);
-        let pulse_cap = LOG_FREQ_RANGE[band] as usize + lm * 8;
+        trace!("band {}", band);
+        let pulse_cap = LOG_FREQ_RANGE[band] as i32 + lm as i32 * 8;
         let offset = (pulse_cap >> 1)
             - if dualstereo && n == 2 {
                 QTHETA_OFFSET_TWOPHASE
@@ -1521,11 +1953,11 @@ impl Celt {
             1
         } else {
             let n2 = if dualstereo && n == 2 {
-                2 * n - 2
+                2 * n as i32 - 2
             } else {
-                2 * n - 1
+                2 * n as i32 - 1
             };
-            println!("n2 {} pulse_cap {} b {}", n2, pulse_cap, b);
+            trace!("n2 {} pulse_cap {} b {}", n2, pulse_cap, b);
             let qb = (b - pulse_cap - (4 << 3))
                 .min((b + n2 * offset) / n2)
                 .min(8 << 3);
@@ -1533,11 +1965,11 @@ impl Celt {
             if qb < (1 << 3 >> 1) {
                 1
             } else {
-                ((QN_EXP2[qb & 0x7] as usize >> (14 - (qb >> 3))) + 1) >> 1 << 1
+                ((QN_EXP2[(qb & 0x7) as usize] as usize >> (14 - (qb >> 3))) + 1) >> 1 << 1
             }
         };
 
-        println!("qn {}", qn);
+        trace!("qn {}", qn);
 
         let tell_frac = rd.tell_frac();
         let (itheta, inv) = if qn != 1 {
@@ -1550,7 +1982,7 @@ impl Celt {
             };
             (itheta * 16384 / qn, false)
         } else {
-            let inv = if dualstereo && b as i32 > BITRES && self.remaining2 > BITRES {
+            let inv = if dualstereo && b > BITRES && self.remaining2 > BITRES {
                 rd.decode_logp(2)
             } else {
                 false
@@ -1558,7 +1990,7 @@ impl Celt {
             (0, inv)
         };
 
-        let qalloc = rd.tell_frac() - tell_frac;
+        let qalloc = (rd.tell_frac() - tell_frac) as i32;
 
         let (imid, iside, fill, delta) = if itheta == 0 {
             let imid = 32767;
@@ -1576,7 +2008,8 @@ impl Celt {
             let imid = bitexact::cos(itheta as i16) as i32;
             let iside = bitexact::cos(16384 - itheta as i16) as i32;
             let delta =
-                bitexact::frac_mul16(((n - 1) << 7) as i16, bitexact::log2tan(iside, imid) as i16);
+                bitexact::frac_mul16(((n - 1) << 7) as i16, bitexact::log2tan(iside, imid) as i16)
+                    as i32;
             (imid, iside, fill, delta)
         };
 
@@ -1592,7 +2025,7 @@ impl Celt {
     }
 
     fn rng(&mut self) -> u32 {
-        self.seed = 1664525 * self.seed + 1013904223;
+        self.seed = self.seed.wrapping_mul(1664525).wrapping_add(1013904223);
 
         self.seed
     }
@@ -1661,17 +2094,226 @@ impl Celt {
         cm
     }
 
-    fn decode_band<'a>(
+    fn decode_band_split(
         &mut self,
         rd: &mut RangeDecoder,
         band: usize,
-        mut mid_buf: &'a mut [f32],
-        mut side_buf: Option<&'a mut [f32]>,
-        mut n: usize,
+        mid_buf: &mut [f32],
+        side_buf: &mut [f32],
+        dualstereo: bool,
+        n: usize,
         mut b: i32,
+        blocks: usize,
+        b0: usize,
+        lowband: Option<&[f32]>,
+        lowband_out: Option<&mut [f32]>,
+        lm: isize,
+        level: usize,
+        gain: f32,
+        fill: usize,
+    ) -> usize {
+        let BandInfo {
+            itheta,
+            inv,
+            mid,
+            side,
+            mut delta,
+            qalloc,
+            fill: split_fill,
+        } = self.compute_theta(rd, band, lm, n, b, b0, blocks, dualstereo, fill);
+
+        b -= qalloc;
+
+        trace!("itheta {} delta {}", itheta, delta);
+
+        let cm = if n == 2 && dualstereo {
+            /* This is a special case for N=2 that only works for stereo and takes
+            advantage of the fact that mid and side are orthogonal to encode
+            the side with just one bit. */
+            let sbits = if itheta != 0 && itheta != 16384 {
+                1 << 3
+            } else {
+                0
+            };
+            let mbits = b - sbits;
+            self.remaining2 -= qalloc + sbits;
+
+            let sign = if sbits != 0 { 1 - 2 * rd.rawbits(1) as i32 } else { 1 } as f32;
+
+            let (mid_buf2, side_buf2) = if itheta > 8192 {
+                (&mut side_buf[..], &mut mid_buf[..])
+            } else {
+                (&mut mid_buf[..], &mut side_buf[..])
+            };
+
+            // The side is folded from the original fill, itheta == 16384
+            // would have cleared its low bits.
+            let cm = self.decode_band(
+                rd,
+                band,
+                mid_buf2,
+                None,
+                n,
+                mbits,
+                blocks,
+                lowband,
+                lowband_out,
+                lm,
+                level,
+                gain,
+                fill,
+            );
+
+            side_buf2[0] = -sign * mid_buf2[1];
+            side_buf2[1] = sign * mid_buf2[0];
+
+            for (m, s) in mid_buf[..2].iter_mut().zip(side_buf[..2].iter_mut()) {
+                let v0 = *m * mid;
+                let v1 = *s * side;
+                *m = v0 - v1;
+                *s = v0 + v1;
+            }
+
+            cm
+        } else {
+            if b0 > 1 && !dualstereo && (itheta & 0x3fff) != 0 {
+                if itheta > 8192 {
+                    // Rough approximation for pre-echo masking
+                    delta -= delta >> (4 - lm);
+                } else {
+                    // Forward-masking slope of 1.5 dB per 10 ms
+                    delta = (delta + ((n as i32) << 3 >> (5 - lm))).min(0);
+                }
+            }
+
+            let mut mbits = ((b - delta) / 2).max(0).min(b);
+            let mut sbits = b - mbits;
+            self.remaining2 -= qalloc;
+
+            let next_lowband2 = if dualstereo {
+                None
+            } else {
+                lowband.map(|l| &l[n..])
+            };
+
+            // Only stereo needs to pass on lowband_out, otherwise it is
+            // handled at the end of the recursion.
+            let (next_lowband_out1, next_level) = if dualstereo {
+                (lowband_out, 0)
+            } else {
+                (None, level + 1)
+            };
+
+            // The mid is not scaled in stereo since it is needed normalized
+            // for folding later.
+            let mid_gain = if dualstereo { 1f32 } else { gain * mid };
+            let side_gain = gain * side;
+            let shift = if dualstereo { 0 } else { b0 >> 1 };
+
+            let rebalance = self.remaining2;
+            let cm = if mbits >= sbits {
+                let cm = self.decode_band(
+                    rd,
+                    band,
+                    mid_buf,
+                    None,
+                    n,
+                    mbits,
+                    blocks,
+                    lowband,
+                    next_lowband_out1,
+                    lm,
+                    next_level,
+                    mid_gain,
+                    split_fill,
+                );
+
+                let rebalance = mbits - (rebalance - self.remaining2);
+                if rebalance > 3 << 3 && itheta != 0 {
+                    sbits += rebalance - (3 << 3);
+                }
+
+                cm | self.decode_band(
+                    rd,
+                    band,
+                    side_buf,
+                    None,
+                    n,
+                    sbits,
+                    blocks,
+                    next_lowband2,
+                    None,
+                    lm,
+                    next_level,
+                    side_gain,
+                    split_fill >> blocks,
+                ) << shift
+            } else {
+                let cm = self.decode_band(
+                    rd,
+                    band,
+                    side_buf,
+                    None,
+                    n,
+                    sbits,
+                    blocks,
+                    next_lowband2,
+                    None,
+                    lm,
+                    next_level,
+                    side_gain,
+                    split_fill >> blocks,
+                ) << shift;
+
+                let rebalance = sbits - (rebalance - self.remaining2);
+                if rebalance > 3 << 3 && itheta != 16384 {
+                    mbits += rebalance - (3 << 3);
+                }
+
+                cm | self.decode_band(
+                    rd,
+                    band,
+                    mid_buf,
+                    None,
+                    n,
+                    mbits,
+                    blocks,
+                    lowband,
+                    next_lowband_out1,
+                    lm,
+                    next_level,
+                    mid_gain,
+                    split_fill,
+                )
+            };
+
+            if dualstereo {
+                stereo_merge(mid_buf, side_buf, mid, n);
+            }
+
+            cm
+        };
+
+        if inv {
+            for v in side_buf[..n].iter_mut() {
+                *v *= -1.0;
+            }
+        }
+
+        cm
+    }
+
+    fn decode_band(
+        &mut self,
+        rd: &mut RangeDecoder,
+        band: usize,
+        mid_buf: &mut [f32],
+        side_buf: Option<&mut [f32]>,
+        mut n: usize,
+        b: i32,
         mut blocks: usize,
-        mut lowband: Option<&'a [f32]>,
-        mut lowband_out: Option<&'a mut [f32]>,
+        lowband: Option<&[f32]>,
+        mut lowband_out: Option<&mut [f32]>,
         mut lm: isize,
         level: usize,
         gain: f32,
@@ -1685,12 +2327,7 @@ impl Celt {
 
         let mut time_divide = 0;
         let longblocks = b0 == 1;
-        println!("decode_band N={} lowband_out {}", n, lowband_out.is_some());
-
-        println!("mid_buf");
-        for v in mid_buf[..n].iter() {
-            println!("{:.08}", v);
-        }
+        trace!("decode_band N={} lowband_out {}", n, lowband_out.is_some());
 
         if n == 1 {
             self.decode_band_1(rd, mid_buf, side_buf, lowband_out);
@@ -1704,16 +2341,7 @@ impl Celt {
             let mut tf_change = self.tf_change[band];
             let recombine = if tf_change > 0 { tf_change } else { 0 };
 
-            println!("recombine {}", recombine);
-
-            if let Some(ref lowband) = lowband {
-                println!("lowband");
-                for v in lowband.iter() {
-                    println!("{:.08}", v);
-                }
-            }
-
-            if b0 > 1 || (recombine != 0 || ((n_b & 1) == 0 && tf_change < 0)) {}
+            trace!("recombine {}", recombine);
 
             for k in 0..recombine {
                 if let Some(ref mut lowband_in) = lowband {
@@ -1726,10 +2354,9 @@ impl Celt {
 
             blocks >>= recombine;
             n_b <<= recombine;
-            println!("blocks {} N_B {}", blocks, n_b);
+            trace!("blocks {} N_B {}", blocks, n_b);
             while (n_b & 1) == 0 && tf_change < 0 {
                 if let Some(ref mut lowband_in) = lowband {
-                    println!("EDIT");
                     haar1(lowband_in.to_mut(), n_b, blocks);
                 };
 
@@ -1744,7 +2371,7 @@ impl Celt {
             b0 = blocks;
             n_b0 = n_b;
 
-            println!("B0 {}", b0);
+            trace!("B0 {}", b0);
             if b0 > 1 {
                 if let Some(ref mut lowband_in) = lowband {
                     deinterleave_hadamard(
@@ -1763,120 +2390,46 @@ impl Celt {
         };
 
         let cache = &CACHE_BITS[CACHE_INDEX[(lm + 1) as usize * MAX_BANDS + band] as usize..];
+        let lowband = lowband.as_ref().map(|c| c.as_ref());
 
-        let split = if !dualstereo && lm >= 0 && b > (cache[cache[0] as usize] as i32) + 12 && n > 2
-        {
+        let mut cm = if let Some(side_buf) = side_buf {
+            self.decode_band_split(
+                rd,
+                band,
+                mid_buf,
+                side_buf,
+                true,
+                n,
+                b,
+                blocks,
+                b0,
+                lowband,
+                lowband_out.as_deref_mut(),
+                lm,
+                level,
+                gain,
+                fill,
+            )
+        } else if lm >= 0 && b > (cache[cache[0] as usize] as i32) + 12 && n > 2 {
+            // If we need 1.5 more bit than we can produce, split the band in two.
             n >>= 1;
-            let (split_mid, split_side) = mid_buf.split_at_mut(n);
-
-            mid_buf = split_mid;
-            side_buf = Some(split_side);
-
             lm -= 1;
             if blocks == 1 {
                 fill = (fill & 1) | (fill << 1);
             }
             blocks = (blocks + 1) >> 1;
-            true
-        } else {
-            dualstereo
-        };
-
-        println!("split {} blocks {} lm {}", split as u8, blocks, lm);
-
-        // TODO: move this code out
-        let mut cm = if split {
-            let info = self.compute_theta(
-                rd,
-                band,
-                lm as usize,
-                n,
-                b as usize,
-                b0,
-                blocks,
-                dualstereo,
-                fill,
-            );
-            let BandInfo {
-                itheta,
-                inv: _,
-                mid: _,
-                side: _,
-                delta,
-                qalloc,
-                fill: _,
-            } = info;
-
-            b -= qalloc as i32;
-
-            println!("itheta {} delta {}", itheta, delta);
-            /* This is a special case for N=2 that only works for stereo and takes
-            advantage of the fact that mid and side are orthogonal to encode
-            the side with just one bit. */
-            if n == 2 && dualstereo {
-                let side_buf = side_buf.as_mut().unwrap();
-                let sbits = if itheta != 0 || itheta != 16384 {
-                    1 << 3
-                } else {
-                    0
-                };
-                let mbits = b - sbits;
-                self.remaining2 -= qalloc as i32 + sbits;
-
-                let (mid_buf2, side_buf2) = if itheta > 8192 {
-                    (&mut side_buf[..], &mut mid_buf[..])
-                } else {
-                    (&mut mid_buf[..], &mut side_buf[..])
-                };
 
-                let sign = if sbits != 0 { 1 - 2 * rd.rawbits(1) } else { 1 } as f32;
+            let (split_mid, split_side) = mid_buf[..n * 2].split_at_mut(n);
 
-                let cm = self.decode_band(
-                    rd,
-                    band,
-                    mid_buf2,
-                    None,
-                    n,
-                    mbits,
-                    blocks,
-                    lowband.as_ref().map(|c| c.as_ref()),
-                    lowband_out.as_deref_mut(),
-                    lm,
-                    level,
-                    gain,
-                    fill,
-                );
-                side_buf2[0] = -sign * mid_buf2[1];
-            }
-
-            /*
-                        if let Some(side_buf) = side_buf { // same as dualstereo
-                            if n != 2 {
-                                // stereo_merge
-                            }
-                            if inv {
-                                for v in side_buf[..n].iter_mut() {
-                                    *v *= -1.0;
-                                }
-                            }
-                        }
-            */
-            0
-        } else {
-            self.decode_band_no_split(
-                rd,
-                mid_buf,
-                lowband.as_ref().map(|c| c.as_ref()),
-                n,
-                blocks,
-                gain,
-                cache,
-                b,
-                fill,
+            self.decode_band_split(
+                rd, band, split_mid, split_side, false, n, b, blocks, b0, lowband, None, lm,
+                level, gain, fill,
             )
+        } else {
+            self.decode_band_no_split(rd, mid_buf, lowband, n, blocks, gain, cache, b, fill)
         };
 
-        if level == 0 {
+        if !dualstereo && level == 0 {
             if b0 > 1 {
                 interleave_hadamard(
                     &mut self.scratch,
@@ -1905,15 +2458,13 @@ impl Celt {
 
             if let Some(lowband_out) = lowband_out {
                 let n = (n0 as f32).sqrt();
-                println!("Lowband_out");
                 for (v, &m) in lowband_out[..n0].iter_mut().zip(mid_buf[..n0].iter()) {
                     *v = n * m;
-                    println!("{:.08}", *v);
                 }
             }
 
             cm &= (1 << blocks) - 1;
-            println!("cm {}", cm);
+            trace!("cm {}", cm);
         }
 
         cm
@@ -1934,6 +2485,10 @@ impl Celt {
         const NORM_SIZE: usize = 8 * 100;
         let mut norm_mid = [0f32; NORM_SIZE];
         let mut norm_side = [0f32; NORM_SIZE];
+        let mut lowband_mid_scratch = [0f32; 8 * 22];
+        let mut lowband_side_scratch = [0f32; 8 * 22];
+
+        let total_bits = (rd.len() << 3) as i32 - self.anticollapse_bit as i32;
 
         for i in band.clone() {
             let band_offset = (FREQ_BANDS[i] as usize) << lm;
@@ -1948,11 +2503,11 @@ impl Celt {
                 self.remaining -= consumed;
             }
 
-            self.remaining2 = (rd.available_frac() - 1 - self.anticollapse_bit) as i32;
+            self.remaining2 = total_bits - consumed - 1;
 
             let b = if i <= self.codedband - 1 {
-                println!("rem {} rem2 {}", self.remaining, self.remaining2);
-                let remaining = self.remaining / ((self.codedband - 1).min(3) as i32);
+                trace!("rem {} rem2 {}", self.remaining, self.remaining2);
+                let remaining = self.remaining / ((self.codedband - i).min(3) as i32);
                 (self.remaining2 + 1)
                     .min(self.pulses[i] + remaining)
                     .max(0)
@@ -1961,21 +2516,37 @@ impl Celt {
                 0
             };
 
-            println!("b {}", b);
+            trace!("b {}", b);
 
-            if FREQ_BANDS[i] as i32 - FREQ_RANGE[i] as i32 >= FREQ_BANDS[band.start] as i32
+            if (FREQ_BANDS[i] as i32 - FREQ_RANGE[i] as i32 >= FREQ_BANDS[band.start] as i32
+                || i == band.start + 1)
                 && (update_lowband || lowband_offset == 0)
             {
                 lowband_offset = i;
             }
 
+            if i == band.start + 1 {
+                // Duplicate enough of the first band folding data to be able
+                // to fold the second band, nothing is copied in CELT-only mode.
+                let off = (FREQ_BANDS[band.start] as usize) << lm;
+                let n1 = (FREQ_RANGE[band.start] as usize) << lm;
+                let n2 = (FREQ_RANGE[band.start + 1] as usize) << lm;
+                let src = off + 2 * n1 - n2..off + n1;
+
+                norm_mid.copy_within(src.clone(), off + n1);
+                if self.dual_stereo {
+                    norm_side.copy_within(src, off + n1);
+                }
+            }
+
             let mut cm = [0, 0];
             let effective_lowband = if lowband_offset != 0
                 && (self.spread != SPREAD_AGGRESSIVE || self.blocks > 1 || self.tf_change[i] < 0)
             {
-                let effective_lowband =
-                    FREQ_BANDS[band.start].max(FREQ_BANDS[lowband_offset] - FREQ_RANGE[i]);
-                println!(
+                let effective_lowband = (FREQ_BANDS[band.start] as i32)
+                    .max(FREQ_BANDS[lowband_offset] as i32 - FREQ_RANGE[i] as i32)
+                    as u8;
+                trace!(
                     "effective_lowband {} off {} range {}",
                     effective_lowband, lowband_offset, FREQ_RANGE[i]
                 );
@@ -1983,7 +2554,6 @@ impl Celt {
 
                 for (e, &v) in FREQ_BANDS[..lowband_offset].iter().enumerate().rev() {
                     if v <= effective_lowband {
-                        // println!("start [{}] {}", e, v);
                         foldstart = e;
                         break;
                     }
@@ -1993,12 +2563,11 @@ impl Celt {
 
                 for (e, &v) in FREQ_BANDS[lowband_offset..].iter().enumerate() {
                     if v >= effective_lowband + FREQ_RANGE[i] {
-                        // println!("end [{}] {}", e, v);
                         foldend += e;
                         break;
                     }
                 }
-                println!("fold {} {}", foldstart, foldend);
+                trace!("fold {} {}", foldstart, foldend);
 
                 for j in foldstart..foldend {
                     cm[0] |= self.frames[0].collapse_masks[j] as usize;
@@ -2013,35 +2582,30 @@ impl Celt {
                 None
             };
 
-            println!("cm {} {}", cm[0], cm[1]);
+            trace!("cm {} {}", cm[0], cm[1]);
 
             if self.dual_stereo && i == self.intensity_stereo {
                 self.dual_stereo = false;
-                for j in (FREQ_BANDS[band.start] << lm) as usize..band_offset as usize {
+                for j in (FREQ_BANDS[band.start] as usize) << lm..band_offset {
                     norm_mid[j] = (norm_mid[j] + norm_side[j]) / 2.0;
                 }
             }
 
-            //            let mut lowband_scratch: [f32; 8 * 22] = unsafe { mem::uninitialized() };
-
-            let lowband_off = effective_lowband.map(|e| (e << lm) as usize);
-            let lowband_out_off = if i != band.end {
-                Some(band_offset)
-            } else {
-                None
-            };
-
-            let (lowband_mid, lowband_mid_out) = norm_mid.split_at_mut(band_offset);
             let n = band_size;
+            let lowband_off = effective_lowband.map(|e| (e as usize) << lm);
 
-            let lowband_mid = lowband_off.map(|off| &lowband_mid[off..off + n]);
-            let lowband_mid_out = lowband_out_off.map(|_| lowband_mid_out);
+            let lowband_mid = lowband_off.map(|off| {
+                lowband_mid_scratch[..n].copy_from_slice(&norm_mid[off..off + n]);
+                &lowband_mid_scratch[..n]
+            });
+            let lowband_mid_out = Some(&mut norm_mid[band_offset..]);
 
             if self.dual_stereo {
-                let (lowband_side, lowband_side_out) = norm_side.split_at_mut(band_offset);
-
-                let lowband_side = lowband_off.map(|off| &lowband_side[off..off + n]);
-                let lowband_side_out = lowband_out_off.map(|_| lowband_side_out);
+                let lowband_side = lowband_off.map(|off| {
+                    lowband_side_scratch[..n].copy_from_slice(&norm_side[off..off + n]);
+                    &lowband_side_scratch[..n]
+                });
+                let lowband_side_out = Some(&mut norm_side[band_offset..]);
 
                 cm[0] = self.decode_band(
                     rd,
@@ -2056,7 +2620,6 @@ impl Celt {
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[0],
                 );
 
@@ -2073,24 +2636,23 @@ impl Celt {
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[1],
                 );
             } else {
+                let y = if self.stereo_pkt { Some(y) } else { None };
                 cm[0] = self.decode_band(
                     rd,
                     i,
                     x,
-                    Some(y),
+                    y,
                     band_size,
-                    b / 2,
+                    b,
                     self.blocks,
                     lowband_mid,
                     lowband_mid_out,
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[0] | cm[1],
                 );
                 cm[1] = cm[0];
@@ -2104,10 +2666,83 @@ impl Celt {
         }
     }
 
+    fn decode_final_energy(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
+        let channels = self.stereo_pkt as usize + 1;
+        let mut bits_left = rd.len() as isize - rd.tell() as isize;
+
+        for &priority in &[false, true] {
+            for i in band.clone() {
+                if bits_left < channels as isize {
+                    break;
+                }
+
+                if self.fine_priority[i] != priority || self.fine_bits[i] >= MAX_FINE_BITS {
+                    continue;
+                }
+
+                for frame in self.frames[..channels].iter_mut() {
+                    let q2 = rd.rawbits(1) as f32;
+                    let offset =
+                        (q2 - 0.5) * (1 << (14 - self.fine_bits[i] - 1)) as f32 / 16384.0;
+                    frame.energy[i] += offset;
+                    bits_left -= 1;
+                }
+            }
+        }
+    }
+
+    // See section 4.3.5
+    fn anti_collapse(&mut self, coeff: &mut [f32], channel: usize, band: Range<usize>) {
+        let lm = self.lm;
+
+        for i in band {
+            let n = (FREQ_RANGE[i] as usize) << lm;
+            let start = (FREQ_BANDS[i] as usize) << lm;
+            let x = &mut coeff[start..start + n];
+
+            // depth in 1/8 bits
+            let depth = (1 + self.pulses[i]) / n as i32;
+            let thresh = (-1.0 - 0.125 * depth as f32).exp2();
+            let sqrt_1 = 1.0 / (n as f32).sqrt();
+
+            let frame = &self.frames[channel];
+            let mut prev = [frame.prev_energy[0][i], frame.prev_energy[1][i]];
+            if !self.stereo_pkt {
+                let other = &self.frames[1];
+                prev[0] = prev[0].max(other.prev_energy[0][i]);
+                prev[1] = prev[1].max(other.prev_energy[1][i]);
+            }
+            let ediff = (frame.energy[i] - prev[0].min(prev[1])).max(0.0);
+            let collapse_mask = frame.collapse_masks[i];
+
+            // Short blocks do not have the same energy as long ones
+            let mut r = (1.0 - ediff).exp2();
+            if lm == 3 {
+                r *= std::f32::consts::SQRT_2;
+            }
+            let r = r.min(thresh) * sqrt_1;
+
+            let mut renormalize = false;
+            for k in 0..1 << lm {
+                // Fill the collapsed blocks with noise
+                if collapse_mask & (1 << k) == 0 {
+                    for j in 0..FREQ_RANGE[i] as usize {
+                        x[(j << lm) + k] = if self.rng() & 0x8000 != 0 { r } else { -r };
+                    }
+                    renormalize = true;
+                }
+            }
+
+            if renormalize {
+                renormalize_vector(x, 1.0);
+            }
+        }
+    }
+
     pub fn decode(
         &mut self,
         rd: &mut RangeDecoder,
-        _out_buf: &mut [f32],
+        out_buf: &mut [f32],
         frame_duration: FrameDuration,
         band: Range<usize>,
     ) {
@@ -2117,15 +2752,17 @@ impl Celt {
 
         self.lm = (frame_size / SHORT_BLOCKSIZE).ilog() - 1;
 
-        println!("framebits {} tell {}", rd.len(), rd.tell());
+        trace!("framebits {} tell {}", rd.len(), rd.tell());
 
-        let silence = if rd.available() > 0 {
+        let silence = if rd.tell() >= rd.len() {
+            true
+        } else if rd.tell() == 1 {
             rd.decode_logp(15)
         } else {
-            true
+            false
         };
 
-        println!("silence {}", silence);
+        trace!("silence {}", silence);
 
         if silence {
             // Pretend we are at the end of the buffer
@@ -2143,7 +2780,7 @@ impl Celt {
             false
         };
 
-        println!("duration {}, transient {}", self.lm, transient);
+        trace!("duration {}, transient {}", self.lm, transient);
 
         self.blocks = if transient { 1 << self.lm } else { 1 };
         self.blocksize = frame_size / self.blocks;
@@ -2164,7 +2801,7 @@ impl Celt {
 
         self.decode_coarse_energy(rd, band.clone());
 
-        println!(
+        trace!(
             "available {} tell {} frac {}",
             rd.available(),
             rd.tell(),
@@ -2173,7 +2810,7 @@ impl Celt {
 
         self.decode_tf_changes(rd, band.clone(), transient);
 
-        println!(
+        trace!(
             "available {} tell {} frac {}",
             rd.available(),
             rd.tell(),
@@ -2182,7 +2819,7 @@ impl Celt {
 
         self.decode_allocation(rd, band.clone());
 
-        println!(
+        trace!(
             "available {} tell {} frac {}",
             rd.available(),
             rd.tell(),
@@ -2195,7 +2832,7 @@ impl Celt {
         let mut coeff0 = [0f32; MAX_FRAME_SIZE];
         let mut coeff1 = [0f32; MAX_FRAME_SIZE];
 
-        println!(
+        trace!(
             "available {} tell {} frac {}",
             rd.available(),
             rd.tell(),
@@ -2204,7 +2841,189 @@ impl Celt {
 
         self.decode_bands(rd, band.clone(), &mut coeff0, &mut coeff1);
 
+        let anticollapse = if self.anticollapse_bit != 0 {
+            rd.rawbits(1) != 0
+        } else {
+            false
+        };
+
+        self.decode_final_energy(rd, band.clone());
+
+        if anticollapse {
+            self.anti_collapse(&mut coeff0, 0, band.clone());
+            if self.stereo_pkt {
+                self.anti_collapse(&mut coeff1, 1, band.clone());
+            }
+        }
+
+        denormalize(&self.frames[0].energy, &mut coeff0, band.clone(), self.lm);
+        if self.stereo_pkt {
+            denormalize(&self.frames[1].energy, &mut coeff1, band.clone(), self.lm);
+        }
+
+        let mut imdct_scale = 1.0 / 32768.0;
+
+        if self.stereo_pkt && !self.stereo {
+            // Downmix
+            for (c0, &c1) in coeff0.iter_mut().zip(coeff1.iter()) {
+                *c0 += c1;
+            }
+            imdct_scale *= 0.5;
+        } else if !self.stereo_pkt && self.stereo {
+            // Upmix
+            coeff1.copy_from_slice(&coeff0);
+        }
+
+        if silence {
+            for f in self.frames.iter_mut() {
+                f.energy = [ENERGY_SILENCE; MAX_BANDS];
+            }
+            coeff0 = [0f32; MAX_FRAME_SIZE];
+            coeff1 = [0f32; MAX_FRAME_SIZE];
+        }
+
+        self.synthesis(out_buf, [&coeff0, &coeff1], frame_size, imdct_scale);
+
+        if !self.stereo_pkt {
+            let (f0, f1) = self.frames.split_at_mut(1);
+            f1[0].energy = f0[0].energy;
+        }
+
+        for f in self.frames.iter_mut() {
+            if !transient {
+                f.prev_energy[1] = f.prev_energy[0];
+                f.prev_energy[0] = f.energy;
+            } else {
+                for (p, &e) in f.prev_energy[0].iter_mut().zip(f.energy.iter()) {
+                    *p = p.min(e);
+                }
+            }
+
+            for i in (0..band.start).chain(band.end..MAX_BANDS) {
+                f.prev_energy[0][i] = ENERGY_SILENCE;
+                f.prev_energy[1][i] = ENERGY_SILENCE;
+                f.energy[i] = 0.0;
+            }
+        }
+
         self.seed = rd.range as u32;
+        self.band = band;
+        self.loss_count = 0;
+    }
+
+    fn synthesis(
+        &mut self,
+        out_buf: &mut [f32],
+        coeffs: [&[f32]; 2],
+        frame_size: usize,
+        imdct_scale: f32,
+    ) {
+        let channels = self.stereo as usize + 1;
+        let blocks = self.blocks;
+        let blocksize = self.blocksize;
+        let imdct = &mut self.imdct[if blocks > 1 { 0 } else { self.lm }];
+
+        for (c, (frame, coeff)) in self
+            .frames
+            .iter_mut()
+            .zip(coeffs.iter())
+            .take(channels)
+            .enumerate()
+        {
+            // IMDCT and overlap-add
+            for j in 0..blocks {
+                let dst = &mut frame.buf[1024 + j * blocksize..];
+
+                imdct.imdct15_half(&mut dst[OVERLAP / 2..], &coeff[j..], blocks, imdct_scale);
+                window_overlap(dst);
+            }
+
+            frame.postfilter(frame_size);
+
+            // Keep the last overlap for the next frame
+            frame.buf.copy_within(frame_size..frame_size + 1024 + OVERLAP / 2, 0);
+
+            frame.deemphasis(&mut out_buf[c..], frame_size, channels);
+        }
+    }
+
+    // Replace the lost frame with noise shaped by the decaying band
+    // energies of the previous frames
+    fn conceal_noise(&mut self, out_buf: &mut [f32], frame_size: usize) {
+        let channels = self.stereo as usize + 1;
+        let band = self.band.clone();
+        let lm = self.lm;
+        let decay = if self.loss_count == 0 { 1.5 } else { 0.5 };
+
+        let mut coeff0 = [0f32; MAX_FRAME_SIZE];
+        let mut coeff1 = [0f32; MAX_FRAME_SIZE];
+
+        for (c, coeff) in [&mut coeff0, &mut coeff1].iter_mut().enumerate().take(channels) {
+            for i in band.clone() {
+                let start = (FREQ_BANDS[i] as usize) << lm;
+                let end = (FREQ_BANDS[i + 1] as usize) << lm;
+
+                for v in coeff[start..end].iter_mut() {
+                    *v = (self.rng() as i32 >> 20) as f32;
+                }
+                renormalize_vector(&mut coeff[start..end], 1.0);
+
+                let energy = &mut self.frames[c].energy[i];
+                *energy = (*energy - decay).max(ENERGY_SILENCE);
+            }
+
+            denormalize(&self.frames[c].energy, &mut coeff[..], band.clone(), lm);
+        }
+
+        // Fade the post-filter out
+        self.reset_gains();
+        self.blocks = 1;
+        self.blocksize = frame_size;
+
+        self.synthesis(out_buf, [&coeff0, &coeff1], frame_size, 1.0 / 32768.0);
+    }
+
+    // Repeat the last pitch period, fading it out
+    fn conceal_pitch(&mut self, out_buf: &mut [f32], frame_size: usize) {
+        let channels = self.stereo as usize + 1;
+        let first = self.loss_count == 0;
+
+        let fade = if first {
+            let mut x = [0f32; 1024];
+            for frame in self.frames.iter().take(channels) {
+                for (x, &v) in x.iter_mut().zip(frame.buf.iter()) {
+                    *x += v;
+                }
+            }
+            self.pitch = pitch_search(&x);
+            1.0
+        } else {
+            0.8
+        };
+
+        for (c, frame) in self.frames.iter_mut().take(channels).enumerate() {
+            frame.extrapolate(self.pitch, fade, frame_size, first);
+            frame.deemphasis(&mut out_buf[c..], frame_size, channels);
+        }
+    }
+
+    /// Conceal a lost frame of `frame_duration`
+    ///
+    /// The first losses extend the previous output by its pitch period,
+    /// after that or if the previous frame was hybrid the spectrum is
+    /// filled with noise instead.
+    pub fn conceal(&mut self, out_buf: &mut [f32], frame_duration: FrameDuration) {
+        let frame_size = frame_duration as usize;
+
+        self.lm = (frame_size / SHORT_BLOCKSIZE).ilog() - 1;
+
+        if self.band.start != 0 || self.loss_count >= PLC_MAX_PITCH_LOSSES {
+            self.conceal_noise(out_buf, frame_size);
+        } else {
+            self.conceal_pitch(out_buf, frame_size);
+        }
+
+        self.loss_count += 1;
     }
 }
 
@@ -2364,4 +3183,36 @@ mod test {
 
         assert_eq!(a, b);
     }
+
+    #[test]
+    fn lpc() {
+        let ac = [1.0, 0.5, 0.25];
+        let mut lpc = [0f32; 2];
+
+        super::lpc(&ac, &mut lpc);
+
+        assert_eq!(lpc, [-0.5, 0.0]);
+    }
+
+    #[test]
+    fn pitch_search() {
+        let x: Vec<f32> = (0..1024)
+            .map(|i| ((i % 200) as f32 / 200.0 - 0.5) * 1000.0)
+            .collect();
+
+        assert_eq!(super::pitch_search(&x), 200);
+    }
+
+    #[test]
+    fn smooth_fade() {
+        let from = [1f32; 240];
+        let to = [-1f32; 240];
+        let mut out = [0f32; 240];
+
+        super::smooth_fade(&mut out, &from, &to, 2);
+
+        assert!(out[..2].iter().all(|&v| (v - 1.0).abs() < 1e-3));
+        assert!(out[238..].iter().all(|&v| (v + 1.0).abs() < 1e-3));
+        assert!(out.windows(3).all(|w| w[2] <= w[0]));
+    }
 }
@@ -7,6 +7,7 @@ pub struct IMDCT15 {
     len4: usize,
 
     tmp: Vec<Complex32>,
+    dst: Vec<Complex32>,
     exptab: Vec<Vec<Complex32>>,
     twiddle: Vec<Complex32>,
 }
@@ -61,8 +62,6 @@ fn m_c(inp: Complex32) -> [Complex32; 4] {
     ]
 }
 
-use std::mem;
-
 fn fft5(inp: &[Complex32], stride: usize) -> [Complex32; 5] {
     let z = [
         m_c(inp[1 * stride]),
@@ -82,8 +81,8 @@ fn fft5(inp: &[Complex32], stride: usize) -> [Complex32; 5] {
 }
 
 impl IMDCT15 {
-    fn new(n: usize) -> Self {
-        use std::f32::consts::PI;
+    pub fn new(n: usize) -> Self {
+        use std::f64::consts::PI;
         let len2 = p2len(n);
         let len = len2 * 2;
         let len4 = len2 / 2;
@@ -91,8 +90,8 @@ impl IMDCT15 {
         let mut tmp = Vec::with_capacity(len * 2);
         let twiddle = (len4..len2)
             .map(|i| {
-                let v = 2f32 * PI * (i as f32 + 0.125) / len as f32;
-                Complex32::new(v.cos(), v.sin())
+                let v = 2f64 * PI * (i as f64 + 0.125) / len as f64;
+                Complex32::new(v.cos() as f32, v.sin() as f32)
             }).collect();
 
         let mut exptab: Vec<Vec<Complex32>> = (0..6)
@@ -100,8 +99,8 @@ impl IMDCT15 {
                 let len = p2len(i);
                 (0..len.max(19))
                     .map(|j| {
-                        let v = 2f32 * PI * j as f32 / len as f32;
-                        Complex32::new(v.cos(), v.sin())
+                        let v = 2f64 * PI * j as f64 / len as f64;
+                        Complex32::new(v.cos() as f32, v.sin() as f32)
                     }).collect()
             }).collect();
 
@@ -117,6 +116,7 @@ impl IMDCT15 {
             len2,
             len4,
             tmp,
+            dst: vec![Complex32::default(); len4],
             exptab,
             twiddle,
         }
@@ -136,18 +136,18 @@ impl IMDCT15 {
 
             let e1 = t1 * exptab[i + 5];
             let e2 = t2 * exptab[2 * (i + 5)];
-            out[i] = t0 + e1 + e2;
+            out[i + 5] = t0 + e1 + e2;
 
             let e1 = t1 * exptab[i + 10];
             let e2 = t2 * exptab[2 * i + 5];
-            out[i] = t0 + e1 + e2;
+            out[i + 10] = t0 + e1 + e2;
         }
     }
 
     fn fft_calc(&self, n: usize, out: &mut [Complex32], inp: &[Complex32], stride: usize) {
         if n > 0 {
             let exptab = &self.exptab[n];
-            let len2 = p2len(n);
+            let len2 = p2len(n - 1);
 
             self.fft_calc(n - 1, &mut out[..], &inp, stride * 2);
             self.fft_calc(n - 1, &mut out[len2..], &inp[stride..], stride * 2);
@@ -156,7 +156,7 @@ impl IMDCT15 {
                 let e = out[i + len2] * exptab[i];
                 let o = out[i];
 
-                out[i + len2] = o + e;
+                out[i + len2] = o - e;
                 out[i] += e;
             }
         } else {
@@ -164,37 +164,38 @@ impl IMDCT15 {
         }
     }
 
-    // Assume out is aligned at least by 64
+    // Writes len2 samples to out
     pub fn imdct15_half(&mut self, out: &mut [f32], inp: &[f32], stride: usize, scale: f32) {
-        let mut dst: Vec<Complex32> = unsafe {
-            Vec::from_raw_parts(
-                mem::transmute(out.as_mut_ptr()),
-                out.len() / 2,
-                out.len() / 2,
-            )
-        };
+        let mut dst = std::mem::take(&mut self.dst);
         let len8 = self.len4 / 2;
         let start = (self.len2 - 1) * stride;
 
-        for (i, t) in self.tmp.iter_mut().enumerate() {
+        for (i, t) in self.tmp[..self.len4].iter_mut().enumerate() {
             let re = inp[start - 2 * stride * i];
             let im = inp[2 * stride * i];
             *t = Complex32::new(re, im) * self.twiddle[i];
         }
 
-        self.fft_calc(self.n, &mut dst, &self.tmp, 1);
+        self.fft_calc(self.n - 1, &mut dst, &self.tmp, 1);
 
         for i in 0..len8 {
             let decr = len8 - i - 1;
             let incr = len8 + i;
             let re0im1 = Complex32::new(dst[decr].im, dst[decr].re)
-                * Complex32::new(self.twiddle[decr].im, self.twiddle[decr].im);
+                * Complex32::new(self.twiddle[decr].im, self.twiddle[decr].re);
             let re1im0 = Complex32::new(dst[incr].im, dst[incr].re)
-                * Complex32::new(self.twiddle[incr].im, self.twiddle[incr].im);
+                * Complex32::new(self.twiddle[incr].im, self.twiddle[incr].re);
 
             dst[decr] = Complex32::new(re0im1.re, re1im0.im).scale(scale);
             dst[incr] = Complex32::new(re1im0.re, re0im1.im).scale(scale);
         }
+
+        for (o, d) in out[..self.len2].chunks_exact_mut(2).zip(dst.iter()) {
+            o[0] = d.re;
+            o[1] = d.im;
+        }
+
+        self.dst = dst;
     }
 }
 
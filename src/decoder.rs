@@ -1,190 +1,651 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
 use crate::codec::decoder::*;
 use crate::codec::error::*;
+use crate::data::audiosample::formats::F32;
+use crate::data::audiosample::{ChannelMap, ChannelType};
+use crate::data::frame::{new_default_frame, ArcFrame, AudioInfo, FrameBufferConv};
 use crate::data::packet::Packet as AVPacket;
-use crate::data::frame::ArcFrame;
+use crate::data::timeinfo::TimeInfo;
 
+use crate::header::OpusHead;
 use crate::packet::*;
 
 use crate::entropy::*;
 use crate::silk::Silk;
-use crate::celt::Celt;
+use crate::celt::{smooth_fade, Celt};
 
 struct Des {
     descr: Descr,
 }
 
-struct Dec {
-    extradata: Option<Vec<u8>>,
-    silk: Option<Silk>,
-    celt: Option<Celt>,
+// Extrapolate the start of a frame from the previous `mode`, the
+// concealed audio is faded into the frame decoded in the new one
+fn conceal_transition(silk: &mut Silk, celt: &mut Celt, mode: Mode, out: &mut [f32], channels: usize) {
+    let duration = if out.len() / channels < FrameDuration::Short as usize {
+        FrameDuration::VeryShort
+    } else {
+        FrameDuration::Short
+    };
+
+    if mode != Mode::SILK {
+        celt.conceal(out, duration);
+    }
+
+    if mode != Mode::CELT {
+        // The SILK concealment works in 10ms units
+        let mut buf = [0f32; FrameDuration::Medium as usize * 2];
+        let buf = &mut buf[..FrameDuration::Medium as usize * channels];
+
+        silk.conceal(FrameDuration::Medium);
+        silk.output(buf, channels);
+
+        out.iter_mut().zip(buf.iter()).for_each(|(o, &v)| *o += v);
+    }
 }
 
-impl Dec {
-    fn new() -> Self {
-        Dec { extradata: None, silk: None, celt: None }
+// Cross-fade `from` into the beginning of `out` over 2.5ms, if `from`
+// lasts 5ms its first half replaces the output as is
+fn fade_from(out: &mut [f32], from: &[f32], channels: usize) {
+    let fade = FrameDuration::VeryShort as usize * channels;
+    let start = from.len() - fade;
+    let mut to = [0f32; FrameDuration::VeryShort as usize * 2];
+    let to = &mut to[..fade];
+
+    out[..start].copy_from_slice(&from[..start]);
+    to.copy_from_slice(&out[start..start + fade]);
+    smooth_fade(&mut out[start..], &from[start..], to, channels);
+}
+
+// Vorbis channel order, see [RFC 7845 section 5.1.1.2](https://tools.ietf.org/html/rfc7845#section-5.1.1.2)
+//
+// av-data has no rear center channel type, the one of 6.1 is left
+// without a position.
+fn vorbis_map(channels: usize) -> ChannelMap {
+    use self::ChannelType::*;
+
+    let ids: &[ChannelType] = match channels {
+        1 => &[Mono],
+        2 => &[L, R],
+        3 => &[L, C, R],
+        4 => &[L, R, Ls, Rs],
+        5 => &[L, C, R, Ls, Rs],
+        6 => &[L, C, R, Ls, Rs, LFE],
+        7 => &[L, C, R, Lss, Rss, Mono, LFE],
+        8 => &[L, C, R, Lss, Rss, Ls, Rs, LFE],
+        _ => return ChannelMap::default_map(channels),
+    };
+
+    let mut map = ChannelMap::new();
+    map.add_channels(ids);
+
+    map
+}
+
+// Convert `duration` in the timebase of `t` to samples at 48kHz
+fn duration_samples(t: &TimeInfo, duration: u64) -> usize {
+    match t.timebase {
+        Some(tb) => (duration as i64 * *tb.numer() * 48000 / *tb.denom()) as usize,
+        None => duration as usize,
     }
 }
 
-impl Descriptor for Des {
-    fn create(&self) -> Box<dyn Decoder> {
-        Box::new(Dec::new())
+// Move the start of `t` past `skip` samples at 48kHz
+fn skip_time(mut t: TimeInfo, skip: usize) -> TimeInfo {
+    let skip = match t.timebase {
+        Some(tb) => skip as i64 * *tb.denom() / (*tb.numer() * 48000),
+        None => skip as i64,
+    };
+
+    t.pts = t.pts.map(|pts| pts + skip);
+    t.duration = t.duration.map(|d| d.saturating_sub(skip as u64));
+    t
+}
+
+/// A single elementary stream, mono or coupled stereo
+struct Stream {
+    silk: Silk,
+    celt: Celt,
+    channels: usize,
+    prev_mode: Option<Mode>,
+    prev_redundancy: bool,
+    out: Vec<f32>,
+}
+
+impl Stream {
+    fn new(coupled: bool) -> Self {
+        Stream {
+            silk: Silk::new(coupled),
+            celt: Celt::new(coupled),
+            channels: coupled as usize + 1,
+            prev_mode: None,
+            prev_redundancy: false,
+            out: Vec::new(),
+        }
     }
 
-    fn describe<'a>(&'a self) -> &'a Descr {
-        &self.descr
+    fn flush(&mut self) {
+        self.silk.flush();
+        self.celt.flush();
+        self.prev_mode = None;
+        self.prev_redundancy = false;
+        self.out.clear();
     }
-}
 
-const OPUS_HEAD_SIZE: usize = 19;
+    fn reset_output(&mut self, samples: usize) {
+        self.out.clear();
+        self.out.resize(samples * self.channels, 0.0);
+    }
 
-impl Decoder for Dec {
-        fn set_extradata(&mut self, extra: &[u8]) {
-            self.extradata = Some(Vec::from(extra));
+    fn decode(&mut self, pkt: &Packet) -> Result<()> {
+        let silk = &mut self.silk;
+        let celt = &mut self.celt;
+        let channels = self.channels;
+
+        // Configure the CELT and the SILK decoder with the
+        // frame-invariant, per-packet information
+        if pkt.mode != Mode::CELT {
+            silk.setup(&pkt);
         }
-        fn send_packet(&mut self, pkt: &AVPacket) -> Result<()> {
-            let silk = self.silk.as_mut().unwrap();
-            let celt = self.celt.as_mut().unwrap();
-            let pkt = Packet::from_slice(pkt.data.as_slice())?;
 
-            println!("{:?}", pkt);
+        if pkt.mode != Mode::SILK {
+            celt.setup(&pkt);
+        }
 
-            // Configure the CELT and the SILK decoder with the
-            // frame-invariant, per-packet information
-            if pkt.mode != Mode::CELT {
-                silk.setup(&pkt);
-            }
+        let frame_size = pkt.frame_duration as usize;
 
-            if pkt.mode == Mode::CELT {
-                celt.setup(&pkt);
-            }
+        self.out.clear();
+        self.out.resize(frame_size * pkt.frames.len() * channels, 0.0);
 
-            if pkt.mode == Mode::HYBRID {
-//                unimplemented!();
-            }
+        let mut prev_mode = self.prev_mode;
+        let mut prev_redundancy = self.prev_redundancy;
 
-            // Decode the frames
-            //
-            // If a silk or a hybrid frame is preset, decode the silk part first
-            for frame in pkt.frames {
-                let mut rd = RangeDecoder::new(frame);
-                // println!("Decoding {:?}", frame);
+        // Decode the frames
+        //
+        // If a silk or a hybrid frame is preset, decode the silk part first
+        for (frame, out_buf) in pkt.frames.iter().zip(self.out.chunks_exact_mut(frame_size * channels)) {
+            let mut rd = RangeDecoder::new(frame);
+            // println!("Decoding {:?}", frame);
 
-                if pkt.mode != Mode::CELT {
-                    silk.decode(&mut rd)?;
-                } else {
+            if pkt.mode != Mode::CELT {
+                // Nothing of the CELT-only frames carries over
+                if prev_mode == Some(Mode::CELT) {
                     silk.flush();
                 }
+                silk.decode(&mut rd)?;
+            }
+
+            let size = frame.len();
+            let consumed = rd.tell();
+            let redundancy = if pkt.mode == Mode::HYBRID && consumed + 37 <= size * 8 {
+                rd.decode_logp(12)
+            } else if pkt.mode == Mode::SILK && consumed + 17 <= size * 8 {
+                true
+            } else {
+                false
+            };
+
+            trace!("consumed {} redundancy {}", consumed, redundancy);
+
+            let mut redundant_frame = None;
+            let mut celt_to_silk = false;
+
+            if redundancy {
+                celt_to_silk = rd.decode_logp(1);
 
-                let size = frame.len();
-                let consumed = rd.tell();
-                let redundancy = if pkt.mode == Mode::HYBRID && consumed + 37 <= size * 8 {
-                    rd.decode_logp(12)
-                } else if pkt.mode == Mode::SILK && consumed + 17 <= size * 8 {
-                    true
+                let redundancy_size = if pkt.mode == Mode::HYBRID {
+                    rd.decode_uniform(256) + 2
                 } else {
-                    false
+                    size - (rd.tell() + 7) / 8
                 };
 
-                println!("consumed {} redundancy {}", consumed, redundancy);
+                trace!("redundancy celt_to_silk {} size {}", celt_to_silk, redundancy_size);
 
-                if redundancy {
-                    let redundancy_pos = rd.decode_logp(1);
+                // A valid packet never has it overlap the main frame
+                if redundancy_size <= size && (size - redundancy_size) * 8 >= rd.tell() {
+                    // The redundant frame is stored after the raw bits
+                    rd.shrink(redundancy_size);
+                    redundant_frame = Some(&frame[size - redundancy_size..]);
+                }
+            }
 
-                    let redundancy_size = if pkt.mode == Mode::HYBRID {
-                        rd.decode_uniform(256) + 2
-                    } else {
-                        size - (consumed + 7) / 8
-                    };
+            let band_end = pkt.bandwidth.celt_band();
+            let mut redundant_audio = [0f32; FrameDuration::Short as usize * 2];
+            let redundant_audio = &mut redundant_audio[..FrameDuration::Short as usize * channels];
 
-                    if redundancy_size >= size {
-                        return Err(Error::InvalidData);
-                    }
+            // Switching to or from CELT with no redundant frame to bridge
+            // the two, fade from the previous mode extrapolated instead
+            let transition = match prev_mode {
+                Some(prev) if redundant_frame.is_none() => {
+                    (pkt.mode == Mode::CELT && prev != Mode::CELT && !prev_redundancy)
+                        || (pkt.mode != Mode::CELT && prev == Mode::CELT)
+                }
+                _ => false,
+            };
+            let mut transition_audio = [0f32; FrameDuration::Short as usize * 2];
+            let transition_size = frame_size.min(FrameDuration::Short as usize);
+            let transition_audio = &mut transition_audio[..transition_size * channels];
+
+            if transition {
+                conceal_transition(silk, celt, prev_mode.unwrap(), transition_audio, channels);
+            }
 
-                    let _size = size - redundancy_size;
+            // The 5ms redundant CELT frame either ends the CELT stream
+            // before the SILK data or starts it afterwards
+            if let Some(data) = redundant_frame {
+                if celt_to_silk {
+                    let mut rd = RangeDecoder::new(data);
 
-                    println!("redundancy pos {} size {}", redundancy_pos, redundancy_size);
+                    celt.setup(&pkt);
+                    celt.decode(&mut rd, redundant_audio, FrameDuration::Short, 0..band_end);
+                }
+            }
 
-                    if redundancy_pos {
-                        // decode_redundancy
-                        // celt.flush()
-                    }
+            if pkt.mode != Mode::SILK {
+                let range = if pkt.mode == Mode::HYBRID {
+                    17
+                } else {
+                    0
+                } .. band_end;
+
+                if prev_mode.map_or(false, |m| m != pkt.mode) && !prev_redundancy {
+                    celt.flush();
                 }
 
-                if pkt.mode != Mode::SILK {
-                    let mut out_buf = [0f32; 1024]; // TODO
-                    let range = if pkt.mode == Mode::HYBRID {
-                        17
-                    } else {
-                        0
-                    } .. pkt.bandwidth.celt_band();
+                celt.decode(&mut rd, out_buf, pkt.frame_duration, range)
+            } else if prev_mode == Some(Mode::HYBRID) && !(celt_to_silk && prev_redundancy) {
+                // Let the CELT MDCT fade out the high band decoding a
+                // silence frame
+                let mut rd = RangeDecoder::new(&[0xFF, 0xFF]);
+                let len = FrameDuration::VeryShort as usize * channels;
+
+                celt.setup(&pkt);
+                celt.decode(&mut rd, &mut out_buf[..len], FrameDuration::VeryShort, 0..band_end);
+            }
 
-                    celt.decode(&mut rd, &mut out_buf, pkt.frame_duration, range)
+            if pkt.mode != Mode::CELT {
+                silk.output(out_buf, channels);
+            }
 
+            if let Some(data) = redundant_frame {
+                if celt_to_silk {
+                    if prev_mode != Some(Mode::SILK) || prev_redundancy {
+                        fade_from(out_buf, redundant_audio, channels);
+                    }
+                } else {
+                    let mut rd = RangeDecoder::new(data);
+                    let fade = FrameDuration::VeryShort as usize * channels;
+                    let mut tmp = [0f32; FrameDuration::VeryShort as usize * 2];
+                    let tmp = &mut tmp[..fade];
+
+                    celt.flush();
+                    celt.setup(&pkt);
+                    celt.decode(&mut rd, redundant_audio, FrameDuration::Short, 0..band_end);
+
+                    let start = out_buf.len() - fade;
+                    tmp.copy_from_slice(&out_buf[start..]);
+                    smooth_fade(&mut out_buf[start..], tmp, &redundant_audio[fade..], channels);
                 }
             }
 
+            if transition {
+                fade_from(out_buf, transition_audio, channels);
+            }
 
-            Ok(())
+            prev_mode = Some(pkt.mode);
+            prev_redundancy = redundant_frame.is_some() && !celt_to_silk;
         }
-        fn receive_frame(&mut self) -> Result<ArcFrame> {
-            // self.pending.pop_front().ok_or(ErrorKind::MoreDataNeeded.into())
-            //
-            unimplemented!()
+
+        self.prev_mode = prev_mode;
+        self.prev_redundancy = prev_redundancy;
+
+        Ok(())
+    }
+
+    fn decode_fec(&mut self, pkt: &Packet) -> Result<()> {
+        let frame_size = pkt.frame_duration as usize;
+
+        // Only the SILK layer carries redundancy, nothing can be
+        // recovered from or into a CELT-only stream
+        let frame = match pkt.frames.first() {
+            Some(frame) if pkt.mode != Mode::CELT && self.prev_mode != Some(Mode::CELT) => frame,
+            _ => return self.conceal(frame_size),
+        };
+
+        let mut rd = RangeDecoder::new(frame);
+
+        self.reset_output(frame_size);
+        self.prev_redundancy = false;
+
+        // The LBRR data covers only the SILK layer, the CELT high band
+        // of a hybrid frame is concealed from the previous one
+        if pkt.mode == Mode::HYBRID && self.prev_mode == Some(Mode::HYBRID) {
+            self.celt.conceal(&mut self.out, pkt.frame_duration);
         }
-        fn configure(&mut self) -> Result<()> {
-            use crate::bitstream::byteread::get_i16l;
-
-            let channels;
-            let _sample_rate = 48000;
-            let mut gain_db = 0;
-            let mut streams = 1;
-            let mut coupled_streams = 0;
-            let mut mapping : &[u8] = &[0u8, 1u8];
-            let mut channel_map = false;
-
-            if let Some(ref extradata) = self.extradata {
-                channels = *extradata.get(9).unwrap_or(&2) as usize;
-
-                if extradata.len() >= OPUS_HEAD_SIZE {
-                    gain_db = get_i16l(&extradata[16..=17]);
-                    channel_map = extradata[18] != 0;
+
+        self.silk.setup(&pkt);
+        self.silk.decode_fec(&mut rd)?;
+        self.silk.output(&mut self.out, self.channels);
+
+        Ok(())
+    }
+
+    fn conceal(&mut self, samples: usize) -> Result<()> {
+        let channels = self.channels;
+        let mode = self.prev_mode;
+
+        // SILK works in 10ms units, CELT can go down to 2.5ms
+        let min = match mode {
+            Some(Mode::CELT) | None => FrameDuration::VeryShort,
+            _ => FrameDuration::Medium,
+        };
+
+        if samples % min as usize != 0 {
+            return Err(Error::InvalidData);
+        }
+
+        self.reset_output(samples);
+        self.prev_redundancy = false;
+
+        // Nothing to extrapolate from, the frame is left silent
+        if mode.is_some() {
+            let mut start = 0;
+            while start < samples {
+                let duration = [
+                    FrameDuration::Standard,
+                    FrameDuration::Medium,
+                    FrameDuration::Short,
+                    FrameDuration::VeryShort,
+                ]
+                .iter()
+                .cloned()
+                .find(|&d| d as usize <= samples - start)
+                .unwrap();
+                let len = duration as usize;
+                let out_buf = &mut self.out[start * channels..(start + len) * channels];
+
+                if mode != Some(Mode::SILK) {
+                    self.celt.conceal(out_buf, duration);
                 }
-                if extradata.len() >= OPUS_HEAD_SIZE + 2 + channels {
-                    streams = extradata[OPUS_HEAD_SIZE] as usize;
-                    coupled_streams = extradata[OPUS_HEAD_SIZE + 1] as usize;
-                    if streams + coupled_streams != channels {
-                        unimplemented!()
-                    }
-                    mapping = &extradata[OPUS_HEAD_SIZE + 2 ..]
-                } else {
-                    if channels > 2 || channel_map {
-                        return Err(Error::ConfigurationInvalid);
-                    }
-                    if channels > 1 {
-                        coupled_streams = 1;
-                    }
+
+                if mode != Some(Mode::CELT) {
+                    self.silk.conceal(duration);
+                    self.silk.output(out_buf, channels);
                 }
-            } else {
-                return Err(Error::ConfigurationIncomplete);
+
+                start += len;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Dec {
+    extradata: Option<Vec<u8>>,
+    streams: Vec<Stream>,
+    coupled_streams: usize,
+    mapping: Vec<u8>,
+    pending: VecDeque<ArcFrame>,
+    info: AudioInfo,
+    prev_samples: usize,
+    gain: f32,
+    output_gain: i16,
+    r128_gain: i16,
+    pre_skip: usize,
+}
+
+impl Dec {
+    pub fn new() -> Self {
+        Dec {
+            extradata: None,
+            streams: Vec::new(),
+            coupled_streams: 0,
+            mapping: Vec::new(),
+            pending: VecDeque::with_capacity(1),
+            prev_samples: 0,
+            gain: 1.0,
+            output_gain: 0,
+            r128_gain: 0,
+            pre_skip: 0,
+            info: AudioInfo {
+                samples: 0,
+                sample_rate: 48000,
+                map: ChannelMap::new(),
+                format: Arc::new(F32),
+                block_len: None,
+            },
+        }
+    }
+
+    /// Split a multistream packet in the packets of each stream
+    fn split<'a>(&self, buf: &'a [u8]) -> Result<Vec<Packet<'a>>> {
+        if self.streams.is_empty() {
+            return Err(Error::ConfigurationIncomplete);
+        }
+
+        split_multistream(buf, self.streams.len())
+    }
+
+    /// Copy the decoded channels from sample `skip` on to the output
+    /// ones according to the channel mapping, applying the output gain
+    ///
+    /// Unmapped channels are left silent.
+    fn route(&self, out: &mut [f32], skip: usize) {
+        let channels = self.mapping.len();
+        let coupled = self.coupled_streams;
+
+        for (c, &m) in self.mapping.iter().enumerate() {
+            if m == 255 {
+                continue;
             }
 
-            if channels > 2 {
-                unimplemented!() // TODO: Support properly channel mapping
+            let m = m as usize;
+            let (s, offset) = if m < 2 * coupled {
+                (m / 2, m % 2)
             } else {
-                // println!("channels {}", channels);
-                self.silk = Some(Silk::new(channels > 1));
-                self.celt = Some(Celt::new(channels > 1));
-                // self.info.map = ChannelMap::default_map(channels);
+                (m - coupled, 0)
+            };
+            let stream = &self.streams[s];
+
+            for (o, &v) in out[c..]
+                .iter_mut()
+                .step_by(channels)
+                .zip(stream.out[skip * stream.channels + offset..].iter().step_by(stream.channels))
+            {
+                *o = v * self.gain;
+            }
+        }
+    }
+
+    fn queue(&mut self, samples: usize, t: Option<TimeInfo>) {
+        self.prev_samples = samples;
+
+        // The last packet of a stream may be trimmed to a shorter duration
+        let end = t
+            .as_ref()
+            .and_then(|t| t.duration.map(|d| duration_samples(t, d)))
+            .unwrap_or(samples)
+            .min(samples);
+
+        // The first pre-skip samples are the encoder delay, drop them
+        let skip = self.pre_skip.min(end);
+        self.pre_skip -= skip;
+
+        if skip == end {
+            return;
+        }
+
+        let mut info = self.info.clone();
+        info.samples = end - skip;
+
+        let mut f = new_default_frame(info, t.map(|t| skip_time(t, skip)));
+        self.route(f.buf.as_mut_slice(0).unwrap(), skip);
+
+        self.pending.push_back(Arc::new(f));
+    }
+
+    // Both gains are in Q7.8 dB
+    fn update_gain(&mut self) {
+        let gain = self.output_gain as i32 + self.r128_gain as i32;
+
+        self.gain = 10f32.powf(gain as f32 / (20.0 * 256.0));
+    }
+
+    /// Apply a R128 gain from the OpusTags on top of the OpusHead one
+    ///
+    /// The gain is in Q7.8 dB as returned by `OpusTags::track_gain` and
+    /// `OpusTags::album_gain`, 0 disables it.
+    pub fn set_r128_gain(&mut self, gain: i16) {
+        self.r128_gain = gain;
+        self.update_gain();
+    }
+
+    /// Drop the next `samples` decoded samples as done for the pre-skip
+    ///
+    /// After a flush it trims the pre-roll decoded to land on a seek
+    /// target.
+    pub fn skip(&mut self, samples: usize) {
+        self.pre_skip = samples;
+    }
+
+    /// Recover `samples` of audio lost right before `pkt` from the
+    /// in-band FEC data it carries
+    ///
+    /// The duration must be a multiple of 2.5ms. The last frame worth of
+    /// it is recovered from `pkt`, what precedes it is concealed as done
+    /// by `conceal`. The recovered audio is queued as decoded one, `pkt`
+    /// still has to be decoded with `send_packet` afterwards.
+    pub fn decode_fec(&mut self, pkt: &AVPacket, samples: usize) -> Result<()> {
+        let min = FrameDuration::VeryShort;
+        if samples % min as usize != 0 {
+            return Err(Error::InvalidData);
+        }
+
+        let pkts = self.split(pkt.data.as_slice())?;
+
+        let frame_size = pkts[0].frame_duration as usize;
+        if pkts.iter().any(|p| p.frame_duration as usize != frame_size) {
+            return Err(Error::InvalidData);
+        }
+
+        // Too short a loss to hold the redundant frame
+        if samples < frame_size {
+            return self.conceal_packet(samples, None);
+        }
+
+        if samples > frame_size {
+            self.conceal_packet(samples - frame_size, None)?;
+        }
+
+        for (stream, pkt) in self.streams.iter_mut().zip(pkts.iter()) {
+            stream.decode_fec(pkt)?;
+        }
+
+        self.queue(frame_size, None);
+
+        Ok(())
+    }
+
+    /// Conceal `samples` of lost audio following the last decoded packet
+    ///
+    /// The duration must be a multiple of 10ms, or of 2.5ms for CELT-only
+    /// streams.
+    pub fn conceal(&mut self, samples: usize) -> Result<()> {
+        self.conceal_packet(samples, None)
+    }
+
+    fn conceal_packet(&mut self, samples: usize, t: Option<TimeInfo>) -> Result<()> {
+        if self.streams.is_empty() {
+            return Err(Error::ConfigurationIncomplete);
+        }
+
+        for stream in self.streams.iter_mut() {
+            stream.conceal(samples)?;
+        }
+
+        self.queue(samples, t);
+
+        Ok(())
+    }
+}
+
+impl Descriptor for Des {
+    fn create(&self) -> Box<dyn Decoder> {
+        Box::new(Dec::new())
+    }
+
+    fn describe<'a>(&'a self) -> &'a Descr {
+        &self.descr
+    }
+}
+
+impl Decoder for Dec {
+        fn set_extradata(&mut self, extra: &[u8]) {
+            self.extradata = Some(Vec::from(extra));
+        }
+        fn send_packet(&mut self, pkt: &AVPacket) -> Result<()> {
+            let t = pkt.t.clone();
+
+            // An empty packet signals a loss
+            if pkt.data.is_empty() {
+                let samples = self.prev_samples;
+                return self.conceal_packet(samples, Some(t));
+            }
+
+            let pkts = self.split(pkt.data.as_slice())?;
+
+            trace!("{:?}", pkts);
+
+            for (stream, pkt) in self.streams.iter_mut().zip(pkts.iter()) {
+                stream.decode(pkt)?;
             }
 
-//            sample_rate, channels, streams, coupled_streams, mapping
+            let samples = pkts[0].frame_duration as usize * pkts[0].frames.len();
+
+            self.queue(samples, Some(t));
+
+            Ok(())
+        }
+        fn receive_frame(&mut self) -> Result<ArcFrame> {
+            self.pending.pop_front().ok_or(Error::MoreDataNeeded)
+        }
+        fn configure(&mut self) -> Result<()> {
+            let head = match self.extradata {
+                Some(ref extradata) => OpusHead::from_slice(extradata).map_err(|e| {
+                    warn!("Invalid OpusHead: {}", e);
+                    Error::ConfigurationInvalid
+                })?,
+                None => return Err(Error::ConfigurationIncomplete),
+            };
+
+            self.streams = (0..head.streams)
+                .map(|s| Stream::new(s < head.coupled_streams))
+                .collect();
+            self.coupled_streams = head.coupled_streams;
+            self.mapping = head.mapping;
+            self.info.map = match head.mapping_family {
+                0 | 1 => vorbis_map(head.channels),
+                _ => ChannelMap::default_map(head.channels),
+            };
+
+            self.output_gain = head.output_gain;
+            self.update_gain();
+            self.pre_skip = head.pre_skip as usize;
 
             Ok(())
         }
 
         fn flush(&mut self) -> Result<()> {
-            // self.dec.as_mut().unwrap().reset();
-            unimplemented!()
+            for stream in self.streams.iter_mut() {
+                stream.flush();
+            }
+
+            self.pending.clear();
+            self.prev_samples = 0;
+
+            Ok(())
         }
     }
 
@@ -200,6 +661,8 @@ pub const OPUS_DESCR: &dyn Descriptor = &Des {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::data::frame::MediaKind;
+    use crate::data::rational::Rational64;
     use matroska::demuxer::*;
     use crate::format::demuxer::Context;
     use crate::format::demuxer::Event;
@@ -223,8 +686,14 @@ mod test {
             if let Ok(ev) = ctx.read_event() {
                 match ev {
                     Event::NewPacket(p) => {
-                        println!("{:?}", p);
+                        trace!("{:?}", p);
                         d.send_packet(&p).unwrap();
+                        while let Ok(f) = d.receive_frame() {
+                            if let MediaKind::Audio(ref info) = f.kind {
+                                assert_eq!(info.map.len(), 2);
+                                assert!(info.samples > 0);
+                            }
+                        }
                     },
                     _ => unreachable!(),
                 }
@@ -232,6 +701,277 @@ mod test {
         }
     }
 
+    // A coupled CELT stream and a mono SILK one in 3.0 Vorbis order
+    const MULTISTREAM_HEAD: &[u8] = &[
+        79, 112, 117, 115, 72, 101, 97, 100, 1, 3, 0, 0, 128, 187, 0, 0, 0, 0, 1, 2, 1, 0, 2, 1,
+    ];
+
+    const MULTISTREAM_PACKETS: &[&[u8]] = &[
+        &[
+            156, 19, 202, 93, 241, 195, 252, 71, 168, 237, 8, 120, 172, 103, 150, 107, 192, 61,
+            23, 241, 215, 8, 132, 193, 244, 162, 4, 17, 97, 249, 53,
+        ],
+        &[
+            156, 19, 192, 111, 117, 20, 59, 188, 190, 222, 78, 80, 102, 90, 178, 165, 48, 234,
+            56, 17, 220, 8, 150, 221, 27, 134, 252, 69, 9, 89, 141, 140,
+        ],
+    ];
+
+    #[test]
+    fn vorbis_order() {
+        use self::ChannelType::*;
+
+        // 5.1 as laid out by libopus, two coupled streams and two mono ones
+        let surround: &[u8] = &[
+            79, 112, 117, 115, 72, 101, 97, 100, 1, 6, 0, 0, 128, 187, 0, 0, 0, 0, 1, 4, 2, 0, 4,
+            1, 2, 3, 5,
+        ];
+
+        for &(head, ids) in &[
+            (MULTISTREAM_HEAD, &[L, C, R][..]),
+            (surround, &[L, C, R, Ls, Rs, LFE][..]),
+        ] {
+            let mut d = Dec::new();
+
+            d.set_extradata(head);
+            d.configure().unwrap();
+
+            assert_eq!(d.info.map.len(), ids.len());
+            for (i, &id) in ids.iter().enumerate() {
+                assert_eq!(d.info.map.get_channel(i), id);
+            }
+        }
+    }
+
+    #[test]
+    fn multistream() {
+        // Samples 300..304 of the second frame as decoded by libopus
+        let reference = [
+            904, 2429, -3290, 627, 2079, -3554, 313, 1790, -3805, -13, 1573, -4041,
+        ];
+
+        let mut d = Dec::new();
+
+        d.set_extradata(MULTISTREAM_HEAD);
+        d.configure().unwrap();
+
+        for data in MULTISTREAM_PACKETS {
+            let mut p = AVPacket::new();
+            p.data = data.to_vec();
+            d.send_packet(&p).unwrap();
+        }
+
+        let _ = d.receive_frame().unwrap();
+        let f = d.receive_frame().unwrap();
+
+        if let MediaKind::Audio(ref info) = f.kind {
+            assert_eq!(info.map.len(), 3);
+            assert_eq!(info.samples, 960);
+        }
+
+        let out: &[f32] = f.buf.as_slice(0).unwrap();
+
+        for (&o, &r) in out[300 * 3..].iter().zip(reference.iter()) {
+            assert!(((o * 32768.0).round() as i32 - r).abs() <= 48);
+        }
+    }
+
+    #[test]
+    fn flush() {
+        let decode = |d: &mut Dec, data: &[u8]| {
+            let mut p = AVPacket::new();
+            p.data = data.to_vec();
+            d.send_packet(&p).unwrap();
+            d.receive_frame().unwrap()
+        };
+
+        let mut d = Dec::new();
+
+        d.set_extradata(MULTISTREAM_HEAD);
+        d.configure().unwrap();
+
+        let first = decode(&mut d, MULTISTREAM_PACKETS[0]);
+
+        decode(&mut d, MULTISTREAM_PACKETS[1]);
+        d.send_packet(&AVPacket::new()).unwrap();
+        d.flush().unwrap();
+
+        assert!(d.receive_frame().is_err());
+
+        // Nothing carries over, the stream decodes as from the start
+        let f = decode(&mut d, MULTISTREAM_PACKETS[0]);
+        let out: &[f32] = f.buf.as_slice(0).unwrap();
+        let reference: &[f32] = first.buf.as_slice(0).unwrap();
+
+        assert_eq!(out, reference);
+    }
+
+    #[test]
+    fn gain_and_pre_skip() {
+        let decode = |head: &[u8], r128_gain| {
+            let mut d = Dec::new();
+            let mut p = AVPacket::new();
+
+            d.set_extradata(head);
+            d.configure().unwrap();
+            d.set_r128_gain(r128_gain);
+
+            p.data = MULTISTREAM_PACKETS[0].to_vec();
+            d.send_packet(&p).unwrap();
+            d.receive_frame().unwrap()
+        };
+
+        // 312 samples of pre-skip and a +6.02dB gain
+        let mut head = MULTISTREAM_HEAD.to_vec();
+        head[10..12].copy_from_slice(&312u16.to_le_bytes());
+        head[16..18].copy_from_slice(&1541i16.to_le_bytes());
+
+        let reference = decode(MULTISTREAM_HEAD, 0);
+        let f = decode(&head, 0);
+        // The R128 gain adds up to the OpusHead one
+        let normalized = decode(&head, -1541);
+
+        if let MediaKind::Audio(ref info) = f.kind {
+            assert_eq!(info.samples, 960 - 312);
+        }
+
+        let reference: &[f32] = reference.buf.as_slice(0).unwrap();
+        let out: &[f32] = f.buf.as_slice(0).unwrap();
+
+        let normalized: &[f32] = normalized.buf.as_slice(0).unwrap();
+
+        for ((&o, &n), &r) in out.iter().zip(normalized.iter()).zip(reference[312 * 3..].iter()) {
+            assert!((o - 2.0 * r).abs() < 1e-3);
+            assert!((n - r).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn pre_skip_pts() {
+        let mut d = Dec::new();
+        let mut p = AVPacket::new();
+
+        let mut head = MULTISTREAM_HEAD.to_vec();
+        head[10..12].copy_from_slice(&312u16.to_le_bytes());
+
+        d.set_extradata(&head);
+        d.configure().unwrap();
+
+        // The pre-skip samples precede the start of the stream
+        p.data = MULTISTREAM_PACKETS[0].to_vec();
+        p.t = TimeInfo {
+            pts: Some(-312),
+            duration: Some(960),
+            timebase: Some(Rational64::new(1, 48000)),
+            ..Default::default()
+        };
+        d.send_packet(&p).unwrap();
+
+        let f = d.receive_frame().unwrap();
+
+        assert_eq!(f.t.pts, Some(0));
+        assert_eq!(f.t.duration, Some(960 - 312));
+    }
+
+    // A mono stream on the first and the third channel of a 3.0 layout
+    const ROUTING_HEAD: &[u8] = &[
+        79, 112, 117, 115, 72, 101, 97, 100, 1, 3, 0, 0, 128, 187, 0, 0, 0, 0, 1, 1, 0, 0, 255, 0,
+    ];
+
+    const ROUTING_PACKETS: &[&[u8]] = &[
+        &[8, 132, 193, 244, 162, 4, 17, 97, 249, 53],
+        &[8, 150, 221, 27, 134, 252, 69, 9, 89, 141, 140],
+    ];
+
+    #[test]
+    fn routing() {
+        let mut d = Dec::new();
+
+        d.set_extradata(ROUTING_HEAD);
+        d.configure().unwrap();
+
+        for data in ROUTING_PACKETS {
+            let mut p = AVPacket::new();
+            p.data = data.to_vec();
+            d.send_packet(&p).unwrap();
+        }
+
+        let _ = d.receive_frame().unwrap();
+        let f = d.receive_frame().unwrap();
+
+        let out: &[f32] = f.buf.as_slice(0).unwrap();
+
+        assert!(out.iter().any(|&v| v != 0.0));
+        for s in out.chunks_exact(3) {
+            assert_eq!(s[0], s[2]);
+            assert_eq!(s[1], 0.0);
+        }
+    }
+
+    const STEREO_HEAD: &[u8] = &[
+        79, 112, 117, 115, 72, 101, 97, 100, 1, 2, 0, 0, 128, 187, 0, 0, 0, 0, 0,
+    ];
+
+    // 20ms stereo SILK packet carrying LBRR frames
+    const LBRR_PACKET: &[u8] = &[
+        76, 72, 1, 188, 115, 165, 182, 252, 235, 14, 220, 67, 116, 144, 37, 120, 224, 173,
+        109, 51, 56, 144, 211, 35, 212, 176, 132, 47, 39, 225, 26, 62, 151, 90, 0, 45, 94,
+        161, 163, 11, 250, 28, 196, 141, 39, 21, 36,
+    ];
+
+    #[test]
+    fn decode_fec_first() {
+        let mut d = Dec::new();
+        let mut p = AVPacket::new();
+
+        d.set_extradata(STEREO_HEAD);
+        d.configure().unwrap();
+
+        // Nothing precedes the lost packet, it is still recovered
+        p.data = LBRR_PACKET.to_vec();
+        d.decode_fec(&p, 960).unwrap();
+
+        let f = d.receive_frame().unwrap();
+
+        if let MediaKind::Audio(ref info) = f.kind {
+            assert_eq!(info.samples, 960);
+        }
+
+        let out: &[f32] = f.buf.as_slice(0).unwrap();
+        assert!(out.iter().all(|v| v.is_finite()));
+        assert!(out.iter().any(|&v| v != 0.0));
+
+        d.send_packet(&p).unwrap();
+        assert!(d.receive_frame().is_ok());
+    }
+
+    #[test]
+    fn decode_fec_lost() {
+        let mut d = Dec::new();
+        let mut p = AVPacket::new();
+
+        d.set_extradata(ROUTING_HEAD);
+        d.configure().unwrap();
+
+        p.data = ROUTING_PACKETS[0].to_vec();
+        d.send_packet(&p).unwrap();
+        d.receive_frame().unwrap();
+
+        // 40ms lost before a 20ms packet, the first half is concealed
+        p.data = ROUTING_PACKETS[1].to_vec();
+        assert!(d.decode_fec(&p, 1000).is_err());
+        d.decode_fec(&p, 1920).unwrap();
+
+        let mut samples = 0;
+        while let Ok(f) = d.receive_frame() {
+            if let MediaKind::Audio(ref info) = f.kind {
+                samples += info.samples;
+            }
+        }
+
+        assert_eq!(samples, 1920);
+    }
+
     #[interpolate_test(n01, 1)]
     #[interpolate_test(n02, 2)]
     #[interpolate_test(n03, 3)]
@@ -61,7 +61,7 @@ impl<'a> BitReadFill for UnpaddedBitReadBE<'a> {
         let v = self.index < self.buffer.len();
 
         if !v {
-            println!("*** Ending *** {}", self.buffer.len());
+            trace!("*** Ending *** {}", self.buffer.len());
         }
         v
     }
@@ -290,7 +290,7 @@ impl<'a> RangeDecoder<'a> {
     fn normalize(&mut self) {
         while self.range <= CODE_BOT {
             let v = self.bits.get_bits_32(SYM_BITS);
-            println!("val {} range {} normalize {}", self.value, self.range, v);
+            trace!("val {} range {} normalize {}", self.value, self.range, v);
             let v = v as usize ^ SYM_MAX;
             self.value = ((self.value << SYM_BITS) | v) & (CODE_TOP - 1);
             self.range <<= SYM_BITS;
@@ -359,7 +359,7 @@ impl<'a> RangeDecoder<'a> {
         let dist = icdf.dist;
         let (scale, sym) = self.get_scale_symbol(total);
         let k = dist.iter().position(|v| *v > sym).unwrap();
-        println!(
+        trace!(
             "icdf val {} range {} k {}" /* dist {:?}" */,
             self.value, self.range, k, /* dist */
         );
@@ -398,12 +398,22 @@ impl<'a> RangeDecoder<'a> {
 
     #[inline(always)]
     pub fn available(&self) -> usize {
-        self.size_in_bits - self.tell()
+        self.size_in_bits.saturating_sub(self.tell())
     }
 
     #[inline(always)]
     pub fn available_frac(&self) -> usize {
-        self.size_in_bits * 8 - self.tell_frac()
+        (self.size_in_bits * 8).saturating_sub(self.tell_frac())
+    }
+
+    /// Drop the last `len` bytes from the buffer
+    ///
+    /// Must be called before any raw bit is read.
+    pub fn shrink(&mut self, len: usize) {
+        let buf = self.revs.buffer;
+
+        self.revs = ReverseBitReadLE::new(&buf[..buf.len() - len]);
+        self.size_in_bits -= len * 8;
     }
 }
 
@@ -438,7 +448,8 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
         self.update(scale, k, k + 1, total);
 
         if bits > UNI_BITS {
-            k << (bits - UNI_BITS) | self.rawbits(bits - UNI_BITS)
+            let k = k << (bits - UNI_BITS) | self.rawbits(bits - UNI_BITS);
+            k.min(len - 1)
         } else {
             k
         }
@@ -494,13 +505,13 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
         let k = if symbol < k1 {
             symbol / 3
         } else {
-            symbol - (k0 + 1) /2
+            symbol - (k0 + 1) * 2
         };
 
         if k <= k0 {
             self.update(scale, 3 * (k + 0), 3 * (k + 1), total);
         } else {
-            self.update(scale, 3 * (k + 1) + (k - 1 - k0), 3 * (k0 + 1) + (k - 0 - k0), total);
+            self.update(scale, 3 * (k0 + 1) + (k - 1 - k0), 3 * (k0 + 1) + (k - 0 - k0), total);
         }
 
         k
@@ -0,0 +1,307 @@
+//! Ogg Opus identification and comment headers
+//!
+//! See [RFC 7845 section 5](https://tools.ietf.org/html/rfc7845#section-5)
+
+use std::fmt;
+
+use crate::bitstream::byteread::{get_i16l, get_u16l, get_u32l};
+
+const OPUS_HEAD_MAGIC: &[u8] = b"OpusHead";
+const OPUS_HEAD_SIZE: usize = 19;
+const OPUS_TAGS_MAGIC: &[u8] = b"OpusTags";
+
+/// Why a header got rejected
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeadError {
+    /// Shorter than the fields it must carry
+    Truncated,
+    /// It does not start with `OpusHead` or `OpusTags`
+    Magic,
+    /// Incompatible major version
+    Version(u8),
+    /// The channel count does not fit the mapping family
+    Channels(usize),
+    /// The mapping family is not supported
+    Family(u8),
+    /// No stream or more coupled streams than streams
+    Streams(usize, usize),
+    /// The channel `.0` maps to the non-existent decoded channel `.1`
+    Mapping(usize, u8),
+}
+
+impl fmt::Display for HeadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::HeadError::*;
+        match *self {
+            Truncated => write!(f, "truncated header"),
+            Magic => write!(f, "missing magic signature"),
+            Version(v) => write!(f, "unsupported version {}", v),
+            Channels(c) => write!(f, "invalid channel count {}", c),
+            Family(m) => write!(f, "unsupported mapping family {}", m),
+            Streams(s, c) => write!(f, "invalid stream count {} with {} coupled", s, c),
+            Mapping(c, m) => write!(f, "channel {} mapped to invalid channel {}", c, m),
+        }
+    }
+}
+
+impl std::error::Error for HeadError {}
+
+/// The decoder configuration stored in the identification header
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: usize,
+    /// Samples at 48kHz to discard from the decoder output
+    pub pre_skip: u16,
+    /// Sample rate of the original input, informational only
+    pub input_sample_rate: u32,
+    /// Gain in Q7.8 dB to apply to the decoder output
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    pub streams: usize,
+    pub coupled_streams: usize,
+    /// Decoded channel for each output channel, 255 for a silent one
+    pub mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    /// Parse and validate an identification header
+    pub fn from_slice(buf: &[u8]) -> Result<Self, HeadError> {
+        if buf.len() < OPUS_HEAD_MAGIC.len() || &buf[..OPUS_HEAD_MAGIC.len()] != OPUS_HEAD_MAGIC {
+            return Err(HeadError::Magic);
+        }
+
+        if buf.len() < OPUS_HEAD_SIZE {
+            return Err(HeadError::Truncated);
+        }
+
+        // Only the minor version may change in a compatible way
+        let version = buf[8];
+        if version >> 4 != 0 {
+            return Err(HeadError::Version(version));
+        }
+
+        let channels = buf[9] as usize;
+        let mapping_family = buf[18];
+
+        let (streams, coupled_streams, mapping) = match mapping_family {
+            // Mono or stereo, a single stream
+            0 => {
+                if channels == 0 || channels > 2 {
+                    return Err(HeadError::Channels(channels));
+                }
+
+                (1, channels - 1, [0, 1][..channels].to_vec())
+            }
+            // Vorbis channel order up to 7.1 or no defined layout
+            1 | 255 => {
+                if channels == 0 || (mapping_family == 1 && channels > 8) {
+                    return Err(HeadError::Channels(channels));
+                }
+
+                if buf.len() < OPUS_HEAD_SIZE + 2 + channels {
+                    return Err(HeadError::Truncated);
+                }
+
+                let streams = buf[OPUS_HEAD_SIZE] as usize;
+                let coupled_streams = buf[OPUS_HEAD_SIZE + 1] as usize;
+                let mapping = &buf[OPUS_HEAD_SIZE + 2..OPUS_HEAD_SIZE + 2 + channels];
+
+                let decoded = streams + coupled_streams;
+                if streams == 0 || coupled_streams > streams || decoded > 255 {
+                    return Err(HeadError::Streams(streams, coupled_streams));
+                }
+
+                if let Some((c, &m)) = mapping
+                    .iter()
+                    .enumerate()
+                    .find(|&(_, &m)| m != 255 && m as usize >= decoded)
+                {
+                    return Err(HeadError::Mapping(c, m));
+                }
+
+                (streams, coupled_streams, mapping.to_vec())
+            }
+            _ => return Err(HeadError::Family(mapping_family)),
+        };
+
+        Ok(OpusHead {
+            version,
+            channels,
+            pre_skip: get_u16l(&buf[10..=11]),
+            input_sample_rate: get_u32l(&buf[12..=15]),
+            output_gain: get_i16l(&buf[16..=17]),
+            mapping_family,
+            streams,
+            coupled_streams,
+            mapping,
+        })
+    }
+}
+
+/// The metadata stored in the comment header
+///
+/// See [RFC 7845 section 5.2](https://tools.ietf.org/html/rfc7845#section-5.2)
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OpusTags {
+    pub vendor: String,
+    /// The `NAME=value` user comments split in name and value
+    pub comments: Vec<(String, String)>,
+}
+
+impl OpusTags {
+    /// Parse a comment header
+    ///
+    /// Comments lacking the `=` separator are skipped.
+    pub fn from_slice(buf: &[u8]) -> Result<Self, HeadError> {
+        if !buf.starts_with(OPUS_TAGS_MAGIC) {
+            return Err(HeadError::Magic);
+        }
+
+        let mut buf = &buf[OPUS_TAGS_MAGIC.len()..];
+
+        let vendor = read_string(&mut buf)?;
+        let count = read_u32(&mut buf)? as usize;
+
+        // Every comment takes at least 4 bytes
+        if count > buf.len() / 4 {
+            return Err(HeadError::Truncated);
+        }
+
+        let mut comments = Vec::with_capacity(count);
+        for _ in 0..count {
+            let comment = read_string(&mut buf)?;
+
+            if let Some(pos) = comment.find('=') {
+                let (name, value) = comment.split_at(pos);
+                comments.push((name.to_owned(), value[1..].to_owned()));
+            }
+        }
+
+        Ok(OpusTags { vendor, comments })
+    }
+
+    /// The value of the first comment named `name`, ignoring the case
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // R128 gains are Q7.8 dB integers relative to the OpusHead gain
+    fn gain(&self, name: &str) -> Option<i16> {
+        self.get(name)?.parse().ok()
+    }
+
+    /// The `R128_TRACK_GAIN` in Q7.8 dB, normalizing the track to the
+    /// EBU R128 reference level
+    pub fn track_gain(&self) -> Option<i16> {
+        self.gain("R128_TRACK_GAIN")
+    }
+
+    /// The `R128_ALBUM_GAIN` in Q7.8 dB, normalizing the album to the
+    /// EBU R128 reference level
+    pub fn album_gain(&self) -> Option<i16> {
+        self.gain("R128_ALBUM_GAIN")
+    }
+}
+
+fn read_u32(buf: &mut &[u8]) -> Result<u32, HeadError> {
+    if buf.len() < 4 {
+        return Err(HeadError::Truncated);
+    }
+
+    let v = get_u32l(&buf[..4]);
+    *buf = &buf[4..];
+
+    Ok(v)
+}
+
+fn read_string(buf: &mut &[u8]) -> Result<String, HeadError> {
+    let len = read_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(HeadError::Truncated);
+    }
+
+    let s = String::from_utf8_lossy(&buf[..len]).into_owned();
+    *buf = &buf[len..];
+
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STEREO: &[u8] = &[
+        79, 112, 117, 115, 72, 101, 97, 100, 1, 2, 56, 1, 128, 187, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn stereo() {
+        let head = OpusHead::from_slice(STEREO).unwrap();
+
+        assert_eq!(head.channels, 2);
+        assert_eq!(head.pre_skip, 312);
+        assert_eq!(head.input_sample_rate, 48000);
+        assert_eq!(head.coupled_streams, 1);
+        assert_eq!(head.mapping, vec![0, 1]);
+    }
+
+    #[test]
+    fn invalid() {
+        let check = |f: &dyn Fn(&mut Vec<u8>), err| {
+            let mut buf = STEREO.to_vec();
+            f(&mut buf);
+            assert_eq!(OpusHead::from_slice(&buf), Err(err));
+        };
+
+        check(&|b| b[0] = b'o', HeadError::Magic);
+        check(&|b| b.truncate(18), HeadError::Truncated);
+        check(&|b| b[8] = 16, HeadError::Version(16));
+        check(&|b| b[9] = 3, HeadError::Channels(3));
+        check(&|b| b[18] = 2, HeadError::Family(2));
+        check(&|b| b[18] = 1, HeadError::Truncated);
+        check(&|b| { b[18] = 1; b.extend(&[1, 2, 0, 1]) }, HeadError::Streams(1, 2));
+        check(&|b| { b[18] = 1; b.extend(&[1, 1, 0, 2]) }, HeadError::Mapping(1, 2));
+    }
+
+    fn tags(comments: &[&str]) -> Vec<u8> {
+        let mut buf = b"OpusTags".to_vec();
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(b"test");
+        buf.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for c in comments {
+            buf.extend_from_slice(&(c.len() as u32).to_le_bytes());
+            buf.extend_from_slice(c.as_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn comments() {
+        let buf = tags(&["TITLE=a=b", "invalid", "r128_track_gain=-512", "R128_ALBUM_GAIN=1.5"]);
+        let t = OpusTags::from_slice(&buf).unwrap();
+
+        assert_eq!(t.vendor, "test");
+        assert_eq!(t.comments.len(), 3);
+        assert_eq!(t.get("title"), Some("a=b"));
+        assert_eq!(t.track_gain(), Some(-512));
+        assert_eq!(t.album_gain(), None);
+    }
+
+    #[test]
+    fn truncated_comments() {
+        let mut buf = tags(&["TITLE=a"]);
+        buf.pop();
+
+        assert_eq!(OpusTags::from_slice(&buf), Err(HeadError::Truncated));
+
+        let mut buf = tags(&[]);
+        let len = buf.len();
+        buf[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(OpusTags::from_slice(&buf), Err(HeadError::Truncated));
+    }
+}
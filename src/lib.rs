@@ -24,11 +24,13 @@ extern crate num_complex as complex;
 extern crate integer_sqrt;
 
 mod entropy;
-mod packet;
 mod maths;
 
 mod silk;
 mod celt;
 
 pub mod decoder;
+pub mod header;
+pub mod packet;
+pub mod ogg;
 
@@ -0,0 +1,694 @@
+//! Ogg Opus demuxing
+//!
+//! See [RFC 3533](https://tools.ietf.org/html/rfc3533) for the Ogg
+//! framing and [RFC 7845](https://tools.ietf.org/html/rfc7845) for the
+//! Opus mapping.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::data::packet::Packet as AVPacket;
+use crate::data::rational::Rational64;
+use crate::data::timeinfo::TimeInfo;
+
+use crate::header::{OpusHead, OpusTags};
+use crate::packet::Packet;
+
+const CAPTURE_PATTERN: &[u8] = b"OggS";
+const PAGE_HEADER_SIZE: usize = 27;
+
+const CONTINUED: u8 = 0x01;
+const BOS: u8 = 0x02;
+const EOS: u8 = 0x04;
+
+const OPUS_HEAD_MAGIC: &[u8] = b"OpusHead";
+
+// A page is at most 27 + 255 + 255 * 255 bytes long
+const MAX_PAGE_SIZE: u64 = 65307;
+
+/// Samples decoded before a seek target for the decoder to converge
+///
+/// See [RFC 7845 section 4.6](https://tools.ietf.org/html/rfc7845#section-4.6)
+pub const PRE_ROLL: u64 = 3840;
+
+// CRC-32 with polynomial 0x04c11db7, no reflection and no final xor
+fn crc32(crc: u32, buf: &[u8]) -> u32 {
+    buf.iter().fold(crc, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u32) << 24), |c, _| {
+            if c & 0x8000_0000 != 0 {
+                (c << 1) ^ 0x04c1_1db7
+            } else {
+                c << 1
+            }
+        })
+    })
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Debug)]
+struct Page {
+    offset: u64,
+    header_type: u8,
+    granule: i64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Read the packets of the first Opus logical stream of an Ogg file
+///
+/// The headers are read on creation, the audio packets can be fed as
+/// they are to the decoder configured with the `extradata`.
+pub struct OggReader<R> {
+    reader: R,
+    pos: u64,
+    data_start: u64,
+    serial: u32,
+    sequence: Option<u32>,
+    head: OpusHead,
+    extradata: Vec<u8>,
+    tags: OpusTags,
+    partial: Vec<u8>,
+    packets: VecDeque<AVPacket>,
+    granule: Option<u64>,
+    eos: bool,
+}
+
+impl<R: Read> OggReader<R> {
+    /// Read the identification and the comment headers
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut r = OggReader {
+            reader,
+            pos: 0,
+            data_start: 0,
+            serial: 0,
+            sequence: None,
+            head: OpusHead::default(),
+            extradata: Vec::new(),
+            tags: OpusTags::default(),
+            partial: Vec::new(),
+            packets: VecDeque::new(),
+            granule: None,
+            eos: false,
+        };
+
+        // The identification header is alone in the first page of the
+        // stream, other multiplexed streams are skipped
+        loop {
+            let page = r.read_page()?.ok_or_else(|| invalid("no Opus stream"))?;
+
+            if page.header_type & BOS != 0 && page.data.starts_with(OPUS_HEAD_MAGIC) {
+                r.serial = page.serial;
+                r.sequence = Some(page.sequence);
+                r.head = OpusHead::from_slice(&page.data).map_err(invalid)?;
+                r.extradata = page.data;
+                break;
+            }
+        }
+
+        // The comment header may span several pages
+        let tags = loop {
+            let (mut packets, _) = r
+                .read_packets()?
+                .ok_or_else(|| invalid("missing OpusTags"))?;
+
+            if !packets.is_empty() {
+                break packets.remove(0);
+            }
+        };
+
+        r.tags = OpusTags::from_slice(&tags).map_err(invalid)?;
+        r.data_start = r.pos;
+
+        Ok(r)
+    }
+
+    /// The identification header
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    /// The raw identification header, to configure the decoder with
+    pub fn extradata(&self) -> &[u8] {
+        &self.extradata
+    }
+
+    /// The comment header
+    pub fn tags(&self) -> &OpusTags {
+        &self.tags
+    }
+
+    /// Granule position of the last page read
+    pub fn granule_position(&self) -> Option<u64> {
+        self.granule
+    }
+
+    /// Read the next audio packet
+    ///
+    /// Its timestamp and duration are in 48kHz samples, the timestamp
+    /// already accounts for the pre-skip.
+    pub fn read_packet(&mut self) -> io::Result<Option<AVPacket>> {
+        while self.packets.is_empty() {
+            if self.eos {
+                return Ok(None);
+            }
+
+            let (packets, granule) = match self.read_packets()? {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+
+            // No packet ends in the page
+            if packets.is_empty() {
+                continue;
+            }
+
+            let granule = granule.max(0) as u64;
+            let durations: Vec<u64> = packets
+                .iter()
+                .map(|p| Packet::from_slice(p).map_or(0, |p| p.sample_count()) as u64)
+                .collect();
+
+            // The granule position marks the end of the last packet in
+            // the page, the last page may end earlier to trim the stream
+            let mut start = match self.granule {
+                Some(prev) if self.eos => prev,
+                _ => granule.saturating_sub(durations.iter().sum()),
+            };
+
+            for (data, duration) in packets.into_iter().zip(durations) {
+                let duration = duration.min(granule.saturating_sub(start));
+                let mut pkt = AVPacket::new();
+
+                pkt.data = data;
+                pkt.t = TimeInfo {
+                    pts: Some(start as i64 - self.head.pre_skip as i64),
+                    duration: Some(duration),
+                    timebase: Some(Rational64::new(1, 48000)),
+                    ..Default::default()
+                };
+
+                self.packets.push_back(pkt);
+                start += duration;
+            }
+
+            self.granule = Some(granule);
+        }
+
+        Ok(self.packets.pop_front())
+    }
+
+    // Read the next page of the stream and return the packets ending
+    // in it along with its granule position
+    fn read_packets(&mut self) -> io::Result<Option<(Vec<Vec<u8>>, i64)>> {
+        let page = loop {
+            match self.read_page()? {
+                Some(page) if page.serial == self.serial => break page,
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        };
+
+        // A page went missing, the packet it carried on cannot be
+        // completed anymore
+        if let Some(sequence) = self.sequence {
+            if page.sequence != sequence.wrapping_add(1) {
+                self.partial.clear();
+            }
+        }
+        self.sequence = Some(page.sequence);
+
+        // The start of the packet got lost, drop what is left of it
+        let mut skip = page.header_type & CONTINUED != 0 && self.partial.is_empty();
+        if page.header_type & CONTINUED == 0 {
+            self.partial.clear();
+        }
+
+        let mut packets = Vec::new();
+        let mut off = 0;
+
+        for &len in page.lacing.iter() {
+            let len = len as usize;
+
+            if !skip {
+                self.partial.extend_from_slice(&page.data[off..off + len]);
+            }
+            off += len;
+
+            // A lacing value below 255 ends the packet
+            if len < 255 {
+                if !skip {
+                    packets.push(std::mem::replace(&mut self.partial, Vec::new()));
+                }
+                skip = false;
+            }
+        }
+
+        self.eos = page.header_type & EOS != 0;
+
+        Ok(Some((packets, page.granule)))
+    }
+
+    // Read exactly buf.len() bytes, false if the stream ended before
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => {
+                self.pos += buf.len() as u64;
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_page(&mut self) -> io::Result<Option<Page>> {
+        let mut header = [0u8; PAGE_HEADER_SIZE];
+
+        loop {
+            // Resynchronize on the capture pattern
+            if !self.fill(&mut header[..4])? {
+                return Ok(None);
+            }
+            while &header[..4] != CAPTURE_PATTERN {
+                header.copy_within(1..4, 0);
+                if !self.fill(&mut header[3..4])? {
+                    return Ok(None);
+                }
+            }
+            let offset = self.pos - 4;
+
+            if !self.fill(&mut header[4..])? {
+                return Ok(None);
+            }
+
+            let mut lacing = vec![0u8; header[26] as usize];
+            if !self.fill(&mut lacing)? {
+                return Ok(None);
+            }
+
+            let mut data = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+            if !self.fill(&mut data)? {
+                return Ok(None);
+            }
+
+            let checksum = u32::from_le_bytes([header[22], header[23], header[24], header[25]]);
+            header[22..26].copy_from_slice(&[0; 4]);
+
+            let crc = crc32(crc32(crc32(0, &header), &lacing), &data);
+
+            // Drop corrupted pages and pages of unknown versions
+            if crc != checksum || header[4] != 0 {
+                continue;
+            }
+
+            let mut granule = [0u8; 8];
+            let mut serial = [0u8; 4];
+            let mut sequence = [0u8; 4];
+            granule.copy_from_slice(&header[6..14]);
+            serial.copy_from_slice(&header[14..18]);
+            sequence.copy_from_slice(&header[18..22]);
+
+            return Ok(Some(Page {
+                offset,
+                header_type: header[5],
+                granule: i64::from_le_bytes(granule),
+                serial: u32::from_le_bytes(serial),
+                sequence: u32::from_le_bytes(sequence),
+                lacing,
+                data,
+            }));
+        }
+    }
+}
+
+impl<R: Read + Seek> OggReader<R> {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.pos = self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    // Read the pages from `offset` on, up to `end`, and return the first
+    // one of the stream closing a packet
+    fn next_granule(&mut self, offset: u64, end: u64) -> io::Result<Option<Page>> {
+        self.seek_to(offset)?;
+
+        while let Some(page) = self.read_page()? {
+            if page.offset >= end {
+                break;
+            }
+            if page.serial == self.serial && page.granule != -1 {
+                return Ok(Some(page));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Find the end of the last page with a granule position up to
+    // `granule` bisecting the stream and then scanning it linearly
+    fn bisect(&mut self, granule: u64) -> io::Result<(u64, Option<u64>)> {
+        let mut lo = self.data_start;
+        let mut hi = self.reader.seek(SeekFrom::End(0))?;
+        let mut best = (self.data_start, None);
+
+        while hi - lo > MAX_PAGE_SIZE {
+            let mid = lo + (hi - lo) / 2;
+
+            match self.next_granule(mid, hi)? {
+                Some(ref page) if (page.granule as u64) <= granule => {
+                    lo = page.offset;
+                }
+                _ => hi = mid,
+            }
+        }
+
+        let mut offset = lo;
+        while let Some(page) = self.next_granule(offset, u64::MAX)? {
+            if page.granule as u64 > granule {
+                break;
+            }
+            offset = self.pos;
+            best = (self.pos, Some(page.granule as u64));
+        }
+
+        Ok(best)
+    }
+
+    /// Seek to the decoded sample `target`
+    ///
+    /// The packets read afterwards start at least `PRE_ROLL` samples
+    /// earlier, if the stream allows it. Returns the number of samples
+    /// to drop once decoded to land exactly on `target`, the decoder has
+    /// to be flushed first.
+    pub fn seek(&mut self, target: u64) -> io::Result<u64> {
+        let pre_skip = self.head.pre_skip as u64;
+        let mut granule = (target + pre_skip).saturating_sub(PRE_ROLL);
+
+        loop {
+            let (offset, prev) = if granule > 0 {
+                self.bisect(granule)?
+            } else {
+                (self.data_start, None)
+            };
+
+            self.seek_to(offset)?;
+            self.partial.clear();
+            self.sequence = None;
+            self.packets.clear();
+            self.granule = prev;
+            self.eos = false;
+
+            let start = match self.read_packet()? {
+                Some(pkt) => {
+                    let start = pkt.t.pts.unwrap_or(0) + pre_skip as i64;
+                    self.packets.push_front(pkt);
+                    start as u64
+                }
+                None => return Ok(0),
+            };
+
+            // The first packet might have started in the previous page,
+            // look further back until there is enough pre-roll
+            if start <= granule || offset == self.data_start {
+                return Ok((target + pre_skip).saturating_sub(start));
+            }
+
+            granule = granule.saturating_sub(start - granule);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEAD: &[u8] = &[
+        79, 112, 117, 115, 72, 101, 97, 100, 1, 2, 56, 1, 128, 187, 0, 0, 0, 0, 0,
+    ];
+
+    const TAGS: &[u8] = b"OpusTags\x04\x00\x00\x00test\x00\x00\x00\x00";
+
+    /// Serialize a page holding `segments`, the last one ends a packet
+    /// unless `open` is set
+    fn page(
+        header_type: u8,
+        granule: i64,
+        serial: u32,
+        sequence: u32,
+        segments: &[&[u8]],
+        open: bool,
+    ) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut data = Vec::new();
+
+        for (i, s) in segments.iter().enumerate() {
+            lacing.extend(std::iter::repeat(255).take(s.len() / 255));
+            if !(open && i == segments.len() - 1) {
+                lacing.push((s.len() % 255) as u8);
+            }
+            data.extend_from_slice(s);
+        }
+
+        let mut buf = CAPTURE_PATTERN.to_vec();
+        buf.push(0);
+        buf.push(header_type);
+        buf.extend_from_slice(&granule.to_le_bytes());
+        buf.extend_from_slice(&serial.to_le_bytes());
+        buf.extend_from_slice(&sequence.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(lacing.len() as u8);
+        buf.extend_from_slice(&lacing);
+        buf.extend_from_slice(&data);
+
+        let crc = crc32(0, &buf);
+        buf[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    #[test]
+    fn packets() {
+        // 20ms CELT packets, the second one spans two pages
+        let p0 = [0xfc; 10];
+        let p1 = [0xfc; 300];
+        let p2 = [0xfc; 20];
+
+        let mut buf = page(BOS, 0, 7, 0, &[HEAD], false);
+        buf.extend(page(BOS, 0, 9, 0, &[b"other stream"], false));
+        buf.extend(page(0, 0, 7, 1, &[TAGS], false));
+        buf.extend(page(0, 960, 7, 2, &[&p0, &p1[..255]], true));
+        buf.extend(page(0, 0, 9, 1, &[b"more data"], false));
+        buf.extend(page(CONTINUED | EOS, 2000, 7, 3, &[&p1[255..], &p2], false));
+
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+
+        assert_eq!(r.head().pre_skip, 312);
+        assert_eq!(r.extradata(), HEAD);
+        assert_eq!(r.tags().vendor, "test");
+
+        let expected: &[(&[u8], i64, u64)] = &[
+            (&p0, -312, 960),
+            (&p1, 648, 960),
+            (&p2, 1608, 80),
+        ];
+
+        for &(data, pts, duration) in expected {
+            let pkt = r.read_packet().unwrap().unwrap();
+
+            assert_eq!(&pkt.data[..], data);
+            assert_eq!(pkt.t.pts, Some(pts));
+            assert_eq!(pkt.t.duration, Some(duration));
+        }
+
+        assert!(r.read_packet().unwrap().is_none());
+        assert_eq!(r.granule_position(), Some(2000));
+    }
+
+    #[test]
+    fn corrupted_page() {
+        let p0 = [0xfc; 10];
+        let p1 = [0xfc; 20];
+
+        let mut buf = b"garbage".to_vec();
+        buf.extend(page(BOS, 0, 7, 0, &[HEAD], false));
+        buf.extend(page(0, 0, 7, 1, &[TAGS], false));
+
+        let mut bad = page(0, 960, 7, 2, &[&p0], false);
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        buf.extend(bad);
+        buf.extend(page(0, 1920, 7, 3, &[&p1], false));
+
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+        let pkt = r.read_packet().unwrap().unwrap();
+
+        assert_eq!(&pkt.data[..], &p1[..]);
+        assert_eq!(pkt.t.pts, Some(960 - 312));
+        assert!(r.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn lost_page() {
+        // The second packet spans three pages, the middle one is corrupted
+        let p0 = [0xfc; 10];
+        let p1 = [0xfc; 600];
+        let p2 = [0xfc; 20];
+
+        let mut buf = page(BOS, 0, 7, 0, &[HEAD], false);
+        buf.extend(page(0, 0, 7, 1, &[TAGS], false));
+        buf.extend(page(0, 960, 7, 2, &[&p0, &p1[..255]], true));
+
+        let mut bad = page(CONTINUED, -1, 7, 3, &[&p1[255..510]], true);
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        buf.extend(bad);
+        buf.extend(page(CONTINUED, 2880, 7, 4, &[&p1[510..], &p2], false));
+
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+
+        assert_eq!(&r.read_packet().unwrap().unwrap().data[..], &p0[..]);
+        assert_eq!(&r.read_packet().unwrap().unwrap().data[..], &p2[..]);
+        assert!(r.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn no_opus() {
+        let buf = page(BOS, 0, 7, 0, &[b"\x01vorbis"], false);
+
+        assert!(OggReader::new(Cursor::new(buf)).is_err());
+    }
+
+    // 20ms CELT packets of varying size, some spanning two pages
+    fn stream(count: usize) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let packets: Vec<Vec<u8>> = (0..count)
+            .map(|i| {
+                let len = 200 + (i * 7919) % 900;
+                let mut p: Vec<u8> = (0..len).map(|j| (i * 31 + j * 17) as u8).collect();
+                p[0] = 0xfc;
+                p
+            })
+            .collect();
+
+        let mut buf = page(BOS, 0, 7, 0, &[HEAD], false);
+        buf.extend(page(0, 0, 7, 1, &[TAGS], false));
+
+        let mut seq = 2;
+        for (i, p) in packets.chunks(3).enumerate() {
+            let granule = ((i + 1) * 3).min(count) as i64 * 960;
+            let segments: Vec<&[u8]> = p.iter().map(|p| &p[..]).collect();
+            buf.extend(page(0, granule, 7, seq, &segments, false));
+            seq += 1;
+        }
+
+        (buf, packets)
+    }
+
+    #[test]
+    fn seek() {
+        let (buf, packets) = stream(300);
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+
+        for &target in &[100_000, 0, 2_000, 287_000, 5_000, 150_123] {
+            let skip = r.seek(target).unwrap();
+            let pkt = r.read_packet().unwrap().unwrap();
+            let pts = pkt.t.pts.unwrap();
+
+            assert_eq!(pts + skip as i64, target as i64);
+            assert!(pts <= (target as i64 - PRE_ROLL as i64).max(-312));
+
+            let index = (pts + 312) as usize / 960;
+            assert_eq!(pkt.data, packets[index]);
+
+            let next = r.read_packet().unwrap().unwrap();
+            assert_eq!(next.t.pts, Some(pts + 960));
+            assert_eq!(next.data, packets[index + 1]);
+        }
+    }
+
+    #[test]
+    fn trimmed_decode() {
+        use crate::codec::decoder::Decoder;
+        use crate::data::frame::MediaKind;
+        use crate::decoder::Dec;
+
+        // The last page ends 80 samples into its packet
+        let p = [0xfc; 20];
+
+        let mut buf = page(BOS, 0, 7, 0, &[HEAD], false);
+        buf.extend(page(0, 0, 7, 1, &[TAGS], false));
+        buf.extend(page(0, 960, 7, 2, &[&p], false));
+        buf.extend(page(EOS, 1040, 7, 3, &[&p], false));
+
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+        let mut d = Dec::new();
+
+        d.set_extradata(r.extradata());
+        d.configure().unwrap();
+
+        let mut samples = 0;
+        while let Some(pkt) = r.read_packet().unwrap() {
+            d.send_packet(&pkt).unwrap();
+            while let Ok(f) = d.receive_frame() {
+                if let MediaKind::Audio(ref info) = f.kind {
+                    samples += info.samples;
+                }
+            }
+        }
+
+        assert_eq!(samples, 1040 - 312);
+    }
+
+    #[test]
+    fn seek_decode() {
+        use crate::codec::decoder::Decoder;
+        use crate::data::frame::FrameBufferConv;
+        use crate::decoder::Dec;
+
+        let decode = |r: &mut OggReader<Cursor<Vec<u8>>>, d: &mut Dec| {
+            let mut out = Vec::new();
+            while let Some(pkt) = r.read_packet().unwrap() {
+                d.send_packet(&pkt).unwrap();
+                while let Ok(f) = d.receive_frame() {
+                    let s: &[f32] = f.buf.as_slice(0).unwrap();
+                    out.extend_from_slice(s);
+                }
+            }
+            out
+        };
+
+        let (buf, _) = stream(60);
+        let mut r = OggReader::new(Cursor::new(buf)).unwrap();
+        let mut d = Dec::new();
+
+        d.set_extradata(r.extradata());
+        d.configure().unwrap();
+
+        let reference = decode(&mut r, &mut d);
+        assert_eq!(reference.len(), (60 * 960 - 312) * 2);
+
+        for &target in &[0, 10_000, 40_000] {
+            d.flush().unwrap();
+            let skip = r.seek(target).unwrap();
+            d.skip(skip as usize);
+
+            let out = decode(&mut r, &mut d);
+            assert_eq!(out.len(), reference.len() - target as usize * 2);
+
+            // The pre-roll brings the decoder close to where it would be
+            let (err, pow) = out
+                .iter()
+                .zip(reference[target as usize * 2..].iter())
+                .fold((0f32, 0f32), |(e, p), (o, r)| (e + (o - r) * (o - r), p + r * r));
+
+            assert!(err * 1e6 <= pow);
+        }
+    }
+}
@@ -1,16 +1,28 @@
 //! Opus Packet parsing
 
+use std::ops::Range;
+
 use crate::codec::error::*;
 
-#[derive(Debug, PartialEq, Clone)]
+/// Frame count code
+///
+/// See [section-3.2](https://tools.ietf.org/html/rfc6716#section-3.2)
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Code {
+    /// One frame
     Single,
+    /// Two frames of equal size
     DoubleEqual,
+    /// Two frames of different sizes
     DoubleVary,
+    /// An arbitrary number of frames
     Multiple,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Coding mode
+///
+/// See [section-3.1](https://tools.ietf.org/html/rfc6716#section-3.1)
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mode {
     SILK,
     CELT,
@@ -61,21 +73,25 @@ pub enum FrameDuration {
     VeryLong = 2880,
 }
 
+/// A parsed Opus packet
+///
+/// The frames borrow the buffer the packet is parsed from.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Packet<'a> {
     code: Code,
     vbr: bool,
     config: usize,
-    pub stereo: bool,
-    pub padding: usize,
-    pub mode: Mode,
-    pub bandwidth: Bandwidth,
-    pub frame_duration: FrameDuration,
-    pub frames: Vec<&'a [u8]>,
+    pub(crate) stereo: bool,
+    pub(crate) padding: usize,
+    pub(crate) mode: Mode,
+    pub(crate) bandwidth: Bandwidth,
+    pub(crate) frame_duration: FrameDuration,
+    pub(crate) frames: Vec<&'a [u8]>,
+    padding_data: &'a [u8],
 }
 
 fn xiph_lacing_u16(buf: &[u8]) -> Result<(usize, usize)> {
-    let mut v = buf[0] as usize;
+    let mut v = *buf.first().ok_or(Error::InvalidData)? as usize;
     if v >= 252 {
         if buf.len() > 1 {
             v += 4 * buf[1] as usize;
@@ -98,7 +114,7 @@ fn xiph_lacing_u32(buf: &[u8]) -> Result<(usize, usize)> {
         v += b;
         o += 1;
         if b < 255 {
-            break;
+            return Ok((o, v as usize));
         } else {
             v -= 1;
         }
@@ -107,7 +123,29 @@ fn xiph_lacing_u32(buf: &[u8]) -> Result<(usize, usize)> {
             return Err(Error::InvalidData);
         }
     }
-    Ok((o, v as usize))
+
+    // The buffer ended before the length did
+    Err(Error::InvalidData)
+}
+
+fn write_xiph_lacing_u16(out: &mut Vec<u8>, len: usize) {
+    if len < 252 {
+        out.push(len as u8);
+    } else {
+        let b0 = 252 + (len & 0x3);
+        out.push(b0 as u8);
+        out.push(((len - b0) >> 2) as u8);
+    }
+}
+
+// Each 255 adds 254 and continues, the final byte adds itself
+//
+// The lacing and the padding it describes span `pad` bytes overall.
+fn write_xiph_lacing_u32(out: &mut Vec<u8>, pad: usize) {
+    let n = (pad - 1) / 255;
+
+    out.resize(out.len() + n, 255);
+    out.push((pad - 255 * n - 1) as u8);
 }
 
 const MAX_FRAME_SIZE: usize = 1275;
@@ -115,92 +153,149 @@ const MAX_FRAMES: usize = 48;
 const MAX_PACKET_DUR: usize = 5760;
 
 impl<'a> Packet<'a> {
-    fn single_packet(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn single_packet(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::Single;
         self.vbr = false;
-        self.frames.push(buf);
-        Ok(())
+
+        if self_delimited {
+            let (off, len) = xiph_lacing_u16(buf)?;
+            if off + len > buf.len() {
+                return Err(Error::InvalidData);
+            }
+
+            self.frames.push(&buf[off..off + len]);
+            Ok(off + len)
+        } else {
+            self.frames.push(buf);
+            Ok(buf.len())
+        }
     }
 
-    fn double_packet_es(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn double_packet_es(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::DoubleEqual;
         self.vbr = false;
 
-        if buf.len() & 1 != 0 {
+        let (off, len) = if self_delimited {
+            xiph_lacing_u16(buf)?
+        } else {
+            if buf.len() & 1 != 0 {
+                return Err(Error::InvalidData);
+            }
+            (0, buf.len() / 2)
+        };
+
+        if off + 2 * len > buf.len() {
             return Err(Error::InvalidData);
         }
 
-        for b in buf.chunks(buf.len()) {
-            self.frames.push(b);
-        }
-        Ok(())
+        let (b1, b2) = buf[off..off + 2 * len].split_at(len);
+
+        self.frames.push(b1);
+        self.frames.push(b2);
+        Ok(off + 2 * len)
     }
 
-    fn double_packet_va(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn double_packet_va(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::DoubleVary;
         self.vbr = true;
 
-        let (off, len) = xiph_lacing_u16(buf)?;
-        if len + off > buf.len() {
+        let (mut off, len) = xiph_lacing_u16(buf)?;
+
+        let len2 = if self_delimited {
+            let (o, len2) = xiph_lacing_u16(&buf[off..])?;
+            off += o;
+            len2
+        } else {
+            buf.len().checked_sub(off + len).ok_or(Error::InvalidData)?
+        };
+
+        if off + len + len2 > buf.len() {
             return Err(Error::InvalidData);
         }
 
-        let (b1, b2) = buf[off..].split_at(len);
+        let (b1, b2) = buf[off..off + len + len2].split_at(len);
 
         self.frames.push(b1);
         self.frames.push(b2);
-        Ok(())
+        Ok(off + len + len2)
     }
 
-    fn multiple_packet(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn multiple_packet(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::Multiple;
-        self.vbr = (buf[0] >> 7) & 0x01 == 1;
 
-        let count = (buf[0] & 0x3f) as usize;
-        let padding = (buf[0] >> 6) & 0x01 == 1;
+        let b0 = *buf.first().ok_or(Error::InvalidData)?;
+        self.vbr = (b0 >> 7) & 0x01 == 1;
+
+        let count = (b0 & 0x3f) as usize;
+        let padding = (b0 >> 6) & 0x01 == 1;
 
         if count == 0 || count > MAX_FRAMES {
             return Err(Error::InvalidData);
         }
 
-        let buf = if padding {
-            let (off, pad) = xiph_lacing_u32(&buf[1..])?;
+        let mut off = 1;
+        if padding {
+            let (o, pad) = xiph_lacing_u32(&buf[1..])?;
             self.padding = pad;
-            &buf[1 + off .. buf.len() - pad]
-        } else {
-            &buf[1 ..]
-        };
+            off += o;
+        }
+
+        // Without self-delimiting the frames span up to the padding
+        let end = buf
+            .len()
+            .checked_sub(self.padding)
+            .filter(|&end| end >= off)
+            .ok_or(Error::InvalidData)?;
 
+        let mut lens = Vec::with_capacity(count);
         if self.vbr {
-            let mut b = buf;
-            println!("count {} padding {}", count, self.padding);
-            let mut lens = Vec::with_capacity(count - 1);
+            trace!("count {} padding {}", count, self.padding);
             for i in 0..count - 1 {
-                let (off, len) = xiph_lacing_u16(b)?;
-                println!("packet {} {}", i, len);
-                b = &b[off..];
+                let (o, len) = xiph_lacing_u16(&buf[off..end])?;
+                trace!("packet {} {}", i, len);
+                off += o;
                 lens.push(len);
             }
-            for len in lens.iter() {
-                let (b1, rem) = b.split_at(*len);
-                self.frames.push(b1);
-                b = rem;
-            }
+            let last = if self_delimited {
+                let (o, len) = xiph_lacing_u16(&buf[off..end])?;
+                off += o;
+                len
+            } else {
+                (end - off)
+                    .checked_sub(lens.iter().sum())
+                    .ok_or(Error::InvalidData)?
+            };
+            lens.push(last);
         } else {
-            let len = buf.len() / count;
-            if len * count != buf.len() || len > MAX_FRAME_SIZE {
-                return Err(Error::InvalidData);
-            }
+            let len = if self_delimited {
+                let (o, len) = xiph_lacing_u16(&buf[off..end])?;
+                off += o;
+                len
+            } else {
+                let len = (end - off) / count;
+                if len * count != end - off {
+                    return Err(Error::InvalidData);
+                }
+                len
+            };
+            lens.resize(count, len);
+        }
 
-            for b in buf.chunks(len) {
-                self.frames.push(b);
+        for len in lens {
+            if len > MAX_FRAME_SIZE || off + len > end {
+                return Err(Error::InvalidData);
             }
+            self.frames.push(&buf[off..off + len]);
+            off += len;
         }
-        Ok(())
+
+        self.padding_data = &buf[off..off + self.padding];
+
+        Ok(off + self.padding)
     }
 
-    pub fn from_slice(buf: &'a [u8]) -> Result<Self> {
-        let mut p = Packet {
+    fn new() -> Self {
+        Packet {
             code: Code::Single,
             stereo: false,
             vbr: false,
@@ -210,109 +305,893 @@ impl<'a> Packet<'a> {
             mode: Mode::HYBRID,
             bandwidth: Bandwidth::Wide,
             frames: Vec::new(),
-        };
-
-        if buf.len() < 1 {
-            unimplemented!();
-        }
-
-        let code = buf[0] & 0x3;
-        let config = (buf[0] >> 3) & 0x1f;
-        p.stereo = (buf[0] >> 2) & 0x01 == 1;
-
-        if code >= 2 && buf.len() < 1 {
-            unimplemented!();
+            padding_data: &[],
         }
+    }
 
-        let buf = &buf[1..];
-
-        println!("code {} config {}", code, config);
-
-        // TODO support self delimited
-        match code {
-            0 => {
-                p.single_packet(&buf)?;
-            },
-            1 => {
-                p.double_packet_es(&buf)?;
-            },
-            2 => {
-                p.double_packet_va(&buf)?;
-            },
-            3 => {
-                p.multiple_packet(&buf)?;
-            }
-            _ => unimplemented!()
-        }
+    fn parse_toc(&mut self, toc: u8) -> u8 {
+        let config = (toc >> 3) & 0x1f;
+        self.config = config as usize;
+        self.stereo = (toc >> 2) & 0x01 == 1;
 
         match config {
             c @ 0 ..= 11 => {
-                p.mode = Mode::SILK;
+                self.mode = Mode::SILK;
                 match c {
                     0 ..= 3 => {
-                        p.bandwidth = Bandwidth::Narrow;
+                        self.bandwidth = Bandwidth::Narrow;
                     },
                     4 ..= 7 => {
-                        p.bandwidth = Bandwidth::Medium;
+                        self.bandwidth = Bandwidth::Medium;
                     },
                     8 ..= 11 => {
-                        p.bandwidth = Bandwidth::Wide;
+                        self.bandwidth = Bandwidth::Wide;
                     },
                     _ => unreachable!(),
                 }
                 match c & 0b11 {
-                    0 => p.frame_duration = FrameDuration::Medium,
-                    1 => p.frame_duration = FrameDuration::Standard,
-                    2 => p.frame_duration = FrameDuration::Long,
-                    3 => p.frame_duration = FrameDuration::VeryLong,
+                    0 => self.frame_duration = FrameDuration::Medium,
+                    1 => self.frame_duration = FrameDuration::Standard,
+                    2 => self.frame_duration = FrameDuration::Long,
+                    3 => self.frame_duration = FrameDuration::VeryLong,
                     _ => unreachable!(),
                 }
             },
             c @ 12 ..= 15 => {
-                p.mode = Mode::HYBRID;
+                self.mode = Mode::HYBRID;
                 match c {
                     12 ..= 13 => {
-                        p.bandwidth = Bandwidth::SuperWide;
+                        self.bandwidth = Bandwidth::SuperWide;
                     },
                     14 ..= 15 => {
-                        p.bandwidth = Bandwidth::Full;
+                        self.bandwidth = Bandwidth::Full;
                     },
                     _ => unreachable!(),
                 }
                 match c & 0b1 {
-                    0 => p.frame_duration = FrameDuration::Medium,
-                    1 => p.frame_duration = FrameDuration::Standard,
+                    0 => self.frame_duration = FrameDuration::Medium,
+                    1 => self.frame_duration = FrameDuration::Standard,
                     _ => unreachable!()
                 }
             },
             c @ 16 ..= 31 => {
-                p.mode = Mode::CELT;
+                self.mode = Mode::CELT;
                 match c {
                     16 ..= 19 => {
-                        p.bandwidth = Bandwidth::Narrow;
+                        self.bandwidth = Bandwidth::Narrow;
                     },
                     20 ..= 23 => {
-                        p.bandwidth = Bandwidth::Wide;
+                        self.bandwidth = Bandwidth::Wide;
                     },
                     24 ..= 27 => {
-                        p.bandwidth = Bandwidth::SuperWide;
+                        self.bandwidth = Bandwidth::SuperWide;
                     }
                     28 ..= 31 => {
-                        p.bandwidth = Bandwidth::Full;
+                        self.bandwidth = Bandwidth::Full;
                     },
                     _ => unreachable!(),
                 }
                 match c & 0b11 {
-                    0 => p.frame_duration = FrameDuration::VeryShort,
-                    1 => p.frame_duration = FrameDuration::Short,
-                    2 => p.frame_duration = FrameDuration::Medium,
-                    3 => p.frame_duration = FrameDuration::Standard,
+                    0 => self.frame_duration = FrameDuration::VeryShort,
+                    1 => self.frame_duration = FrameDuration::Short,
+                    2 => self.frame_duration = FrameDuration::Medium,
+                    3 => self.frame_duration = FrameDuration::Standard,
                     _ => unreachable!(),
                 }
             },
             _ => unreachable!(),
         }
 
-        Ok(p)
+        config
+    }
+
+    fn parse(buf: &'a [u8], self_delimited: bool) -> Result<(Self, usize)> {
+        let mut p = Packet::new();
+
+        let toc = *buf.first().ok_or(Error::InvalidData)?;
+        let code = toc & 0x3;
+        let config = p.parse_toc(toc);
+
+        let buf = &buf[1..];
+
+        trace!("code {} config {}", code, config);
+
+        let consumed = match code {
+            0 => p.single_packet(buf, self_delimited)?,
+            1 => p.double_packet_es(buf, self_delimited)?,
+            2 => p.double_packet_va(buf, self_delimited)?,
+            _ => p.multiple_packet(buf, self_delimited)?,
+        };
+
+        // See section-3.4, R2 and R5
+        if p.frames.iter().any(|f| f.len() > MAX_FRAME_SIZE) || p.sample_count() > MAX_PACKET_DUR {
+            return Err(Error::InvalidData);
+        }
+
+        Ok((p, 1 + consumed))
+    }
+
+    pub fn from_slice(buf: &'a [u8]) -> Result<Self> {
+        Packet::parse(buf, false).map(|(p, _)| p)
+    }
+
+    /// The TOC configuration number, from 0 to 31
+    pub fn config(&self) -> usize {
+        self.config
+    }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    /// Whether the frames may differ in size
+    pub fn is_vbr(&self) -> bool {
+        self.vbr
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.stereo
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth
+    }
+
+    /// Duration in samples at 48kHz of each frame
+    pub fn frame_duration(&self) -> FrameDuration {
+        self.frame_duration
+    }
+
+    pub fn frames(&self) -> &[&'a [u8]] {
+        &self.frames
+    }
+
+    /// Number of padding bytes of a code 3 packet
+    pub fn padding(&self) -> usize {
+        self.padding
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Duration in samples at 48kHz
+    pub fn sample_count(&self) -> usize {
+        self.frame_duration as usize * self.frames.len()
+    }
+
+    // Pad to `len` bytes if requested, forcing the code 3 framing, the
+    // padding starts with `extensions`
+    fn write_framing(
+        &self,
+        out: &mut Vec<u8>,
+        self_delimited: bool,
+        len: Option<usize>,
+        extensions: &[u8],
+    ) -> Result<usize> {
+        let frames = &self.frames;
+        let count = frames.len();
+
+        if count == 0
+            || count > MAX_FRAMES
+            || frames.iter().any(|f| f.len() > MAX_FRAME_SIZE)
+            || self.sample_count() > MAX_PACKET_DUR
+        {
+            return Err(Error::InvalidData);
+        }
+
+        let start = out.len();
+        let toc = (self.config << 3) as u8 | (self.stereo as u8) << 2;
+        let last = frames[count - 1].len();
+        let cbr = frames.iter().all(|f| f.len() == last);
+
+        let lacing_size = |len| if len < 252 { 1 } else { 2 };
+        let mut size = 2 + frames.iter().map(|f| f.len()).sum::<usize>();
+        if !cbr {
+            size += frames[..count - 1].iter().map(|f| lacing_size(f.len())).sum::<usize>();
+        }
+        if self_delimited {
+            size += lacing_size(last);
+        }
+
+        // The lacing of the padding takes a byte more every 254 bytes
+        let ext = extensions.len();
+        let min_pad = if ext > 0 { ext + (ext - 1) / 254 + 1 } else { 0 };
+
+        let pad = match len {
+            Some(len) => len.checked_sub(size).ok_or(Error::InvalidData)?,
+            None => min_pad,
+        };
+
+        if pad < min_pad {
+            return Err(Error::InvalidData);
+        }
+
+        let compact = len.is_none() && ext == 0;
+
+        // Pick the code with the least overhead
+        match count {
+            1 if compact => out.push(toc),
+            2 if compact && cbr => out.push(toc | 1),
+            2 if compact => {
+                out.push(toc | 2);
+                write_xiph_lacing_u16(out, frames[0].len());
+            }
+            _ => {
+                out.push(toc | 3);
+                let vbr = if cbr { 0 } else { 0x80 };
+                let padded = if pad > 0 { 0x40 } else { 0 };
+                out.push(vbr | padded | count as u8);
+                if pad > 0 {
+                    write_xiph_lacing_u32(out, pad);
+                }
+                if !cbr {
+                    for f in &frames[..count - 1] {
+                        write_xiph_lacing_u16(out, f.len());
+                    }
+                }
+            }
+        }
+
+        if self_delimited {
+            write_xiph_lacing_u16(out, last);
+        }
+
+        for f in frames {
+            out.extend_from_slice(f);
+        }
+
+        // Zeros after the extensions are parsed as padding
+        out.extend_from_slice(extensions);
+        out.resize(start + len.unwrap_or(out.len() - start), 0);
+
+        Ok(out.len() - start)
+    }
+
+    /// Serialize the packet to `out` with the most compact framing
+    ///
+    /// The padding is dropped. Returns the number of bytes written.
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<usize> {
+        self.write_framing(out, false, None, &[])
+    }
+
+    /// Serialize the packet to `out` using the self-delimiting framing
+    pub fn write_self_delimited(&self, out: &mut Vec<u8>) -> Result<usize> {
+        self.write_framing(out, true, None, &[])
+    }
+
+    // The compact framing if it fits exactly, the padded one otherwise
+    fn write_padded(&self, out: &mut Vec<u8>, self_delimited: bool, len: usize) -> Result<usize> {
+        let start = out.len();
+        let size = self.write_framing(out, self_delimited, None, &[])?;
+
+        if size != len {
+            out.truncate(start);
+            self.write_framing(out, self_delimited, Some(len), &[])?;
+        }
+
+        Ok(len)
+    }
+
+    /// Serialize the packet padded with zeros to exactly `len` bytes
+    ///
+    /// Fails if the frames do not fit in `len` bytes.
+    pub fn pad_to(&self, len: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        self.write_padded(&mut out, false, len)?;
+
+        Ok(out)
+    }
+
+    /// Serialize the packet without its padding
+    pub fn unpad(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+
+        Ok(out)
+    }
+
+    /// The extensions carried in the padding
+    ///
+    /// See [draft-ietf-mlcodec-opus-extension](https://datatracker.ietf.org/doc/draft-ietf-mlcodec-opus-extension/)
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions {
+            buf: self.padding_data,
+            frame: 0,
+            frames: self.frames.len(),
+        }
+    }
+
+    /// Serialize the packet to `out` carrying `extensions` in the padding
+    ///
+    /// Decoders unaware of the extensions just skip them. Returns the
+    /// number of bytes written.
+    pub fn write_with_extensions(
+        &self,
+        extensions: &[Extension],
+        out: &mut Vec<u8>,
+    ) -> Result<usize> {
+        let mut ext = Vec::new();
+        write_extensions(extensions, self.frames.len(), &mut ext)?;
+
+        self.write_framing(out, false, None, &ext)
+    }
+
+    /// Parse a packet using the self-delimiting framing
+    ///
+    /// See [appendix-B](https://tools.ietf.org/html/rfc6716#appendix-B)
+    ///
+    /// Returns the packet and the number of bytes it spans, the rest of
+    /// the buffer is left to the following packets.
+    pub fn from_slice_self_delimited(buf: &'a [u8]) -> Result<(Self, usize)> {
+        Packet::parse(buf, true)
+    }
+}
+
+/// Side data stored in the padding of a packet
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Extension<'a> {
+    /// Identifier from 2 to 127, up to 31 the payload is at most 1 byte
+    pub id: u8,
+    /// Index of the frame the extension applies to
+    pub frame: usize,
+    pub data: &'a [u8],
+}
+
+const EXT_PADDING: u8 = 0;
+const EXT_SEPARATOR: u8 = 1;
+const EXT_SHORT_MAX: u8 = 31;
+const EXT_MAX: u8 = 127;
+
+/// Iterator over the extensions of a packet
+///
+/// It stops at the first malformed extension, after returning an error.
+#[derive(Debug, Clone)]
+pub struct Extensions<'a> {
+    buf: &'a [u8],
+    frame: usize,
+    frames: usize,
+}
+
+impl<'a> Extensions<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(Error::InvalidData);
+        }
+
+        let (data, rest) = self.buf.split_at(len);
+        self.buf = rest;
+
+        Ok(data)
+    }
+
+    fn parse(&mut self) -> Result<Option<Extension<'a>>> {
+        while let Some((&b, rest)) = self.buf.split_first() {
+            self.buf = rest;
+
+            let id = b >> 1;
+            let l = b & 1 == 1;
+
+            match id {
+                // A single byte of padding or all the rest
+                EXT_PADDING => {
+                    if !l {
+                        self.buf = &[];
+                    }
+                }
+                // Move to the next frame or skip several
+                EXT_SEPARATOR => {
+                    self.frame += if l { self.take(1)?[0] as usize } else { 1 };
+                    if self.frame >= self.frames {
+                        return Err(Error::InvalidData);
+                    }
+                }
+                _ => {
+                    let len = match (id, l) {
+                        (2..=EXT_SHORT_MAX, l) => l as usize,
+                        // The last one spans the rest
+                        (_, false) => self.buf.len(),
+                        // 255 continues the length
+                        (_, true) => {
+                            let mut len = 0;
+                            loop {
+                                let b = self.take(1)?[0];
+                                len += b as usize;
+                                if b < 255 {
+                                    break len;
+                                }
+                            }
+                        }
+                    };
+
+                    let data = self.take(len)?;
+
+                    return Ok(Some(Extension {
+                        id,
+                        frame: self.frame,
+                        data,
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Result<Extension<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = self.parse().transpose();
+        if let Some(Err(_)) = r {
+            self.buf = &[];
+        }
+
+        r
+    }
+}
+
+/// Serialize `extensions` for a packet of `frames` frames to `out`
+///
+/// Every payload carries its length, so that zeros may follow as
+/// padding. Returns the number of bytes written.
+pub fn write_extensions(extensions: &[Extension], frames: usize, out: &mut Vec<u8>) -> Result<usize> {
+    if extensions.iter().any(|e| {
+        e.id <= EXT_SEPARATOR
+            || e.id > EXT_MAX
+            || e.frame >= frames
+            || (e.id <= EXT_SHORT_MAX && e.data.len() > 1)
+    }) {
+        return Err(Error::InvalidData);
+    }
+
+    let start = out.len();
+    let mut frame = 0;
+
+    let mut sorted = extensions.to_vec();
+    sorted.sort_by_key(|e| e.frame);
+
+    for e in sorted {
+        match e.frame - frame {
+            0 => {}
+            1 => out.push(EXT_SEPARATOR << 1),
+            n => out.extend_from_slice(&[EXT_SEPARATOR << 1 | 1, n as u8]),
+        }
+        frame = e.frame;
+
+        if e.id <= EXT_SHORT_MAX {
+            out.push(e.id << 1 | e.data.len() as u8);
+        } else {
+            let len = e.data.len();
+            out.push(e.id << 1 | 1);
+            out.resize(out.len() + len / 255, 255);
+            out.push((len % 255) as u8);
+        }
+
+        out.extend_from_slice(e.data);
+    }
+
+    Ok(out.len() - start)
+}
+
+/// Split a multistream packet in the packets of its `streams` streams
+///
+/// All but the last stream use the self-delimiting framing, all of
+/// them must have the same duration.
+pub(crate) fn split_multistream<'a>(mut buf: &'a [u8], streams: usize) -> Result<Vec<Packet<'a>>> {
+    if streams == 0 {
+        return Err(Error::InvalidData);
+    }
+
+    let mut pkts = Vec::with_capacity(streams);
+
+    for _ in 1..streams {
+        let (pkt, consumed) = Packet::from_slice_self_delimited(buf)?;
+        pkts.push(pkt);
+        buf = &buf[consumed..];
+    }
+
+    pkts.push(Packet::from_slice(buf)?);
+
+    if pkts.iter().any(|p| p.sample_count() != pkts[0].sample_count()) {
+        return Err(Error::InvalidData);
+    }
+
+    Ok(pkts)
+}
+
+/// Pad a multistream packet of `streams` streams to exactly `len` bytes
+///
+/// The padding goes to the last stream.
+pub fn multistream_pad_to(buf: &[u8], len: usize, streams: usize) -> Result<Vec<u8>> {
+    let pkts = split_multistream(buf, streams)?;
+    let (last, pkts) = pkts.split_last().unwrap();
+
+    let mut out = Vec::with_capacity(len);
+    for pkt in pkts {
+        pkt.write_self_delimited(&mut out)?;
+    }
+
+    let size = len.checked_sub(out.len()).ok_or(Error::InvalidData)?;
+    last.write_padded(&mut out, false, size)?;
+
+    Ok(out)
+}
+
+/// Strip the padding from every stream of a multistream packet
+pub fn multistream_unpad(buf: &[u8], streams: usize) -> Result<Vec<u8>> {
+    let pkts = split_multistream(buf, streams)?;
+    let (last, pkts) = pkts.split_last().unwrap();
+
+    let mut out = Vec::with_capacity(buf.len());
+    for pkt in pkts {
+        pkt.write_self_delimited(&mut out)?;
+    }
+    last.write(&mut out)?;
+
+    Ok(out)
+}
+
+/// Merge the frames of several packets in one or split them
+///
+/// All the packets must share the same TOC configuration and channel
+/// count and span up to 120ms overall.
+#[derive(Debug, Default)]
+pub struct Repacketizer<'a> {
+    packet: Option<Packet<'a>>,
+}
+
+impl<'a> Repacketizer<'a> {
+    pub fn new() -> Self {
+        Repacketizer { packet: None }
+    }
+
+    /// Drop the frames collected so far
+    pub fn clear(&mut self) {
+        self.packet = None;
+    }
+
+    /// Append the frames of the packet in `buf`
+    ///
+    /// On error the frames already collected are left untouched.
+    pub fn push(&mut self, buf: &'a [u8]) -> Result<()> {
+        let pkt = Packet::from_slice(buf)?;
+
+        match self.packet {
+            None => self.packet = Some(pkt),
+            Some(ref mut cur) => {
+                if cur.config != pkt.config
+                    || cur.stereo != pkt.stereo
+                    || cur.sample_count() + pkt.sample_count() > MAX_PACKET_DUR
+                {
+                    return Err(Error::InvalidData);
+                }
+
+                cur.frames.extend(pkt.frames);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.packet.as_ref().map_or(0, |p| p.frames.len())
+    }
+
+    /// Write the frames in `range` as a single packet to `out`
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_range(&self, range: Range<usize>, out: &mut Vec<u8>) -> Result<usize> {
+        let cur = self.packet.as_ref().ok_or(Error::InvalidData)?;
+        let frames = cur.frames.get(range).ok_or(Error::InvalidData)?;
+
+        let pkt = Packet {
+            frames: frames.to_vec(),
+            ..cur.clone()
+        };
+
+        pkt.write(out)
+    }
+
+    /// Write all the frames as a single packet to `out`
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<usize> {
+        self.write_range(0..self.frame_count(), out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // See section-3.4 for the rules
+    #[test]
+    fn invalid_r1_empty() {
+        assert!(Packet::from_slice(&[]).is_err());
+    }
+
+    #[test]
+    fn invalid_r2_frame_size() {
+        let mut long = [0u8; 2553];
+        assert!(Packet::from_slice(&long[..1277]).is_err());
+
+        // Two frames of 1276 bytes
+        long[0] = 0x01;
+        assert!(Packet::from_slice(&long).is_err());
+    }
+
+    #[test]
+    fn invalid_r3_equal_frames() {
+        assert!(Packet::from_slice(&[0x01, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn invalid_r4_first_frame() {
+        assert!(Packet::from_slice(&[0x02, 5, 1]).is_err());
+        assert!(Packet::from_slice(&[0x02, 252]).is_err());
+    }
+
+    #[test]
+    fn invalid_r5_frame_count() {
+        assert!(Packet::from_slice(&[0x03]).is_err());
+        assert!(Packet::from_slice(&[0x03, 0x00]).is_err());
+        // 7 frames of 20ms
+        assert!(Packet::from_slice(&[0x0b, 0x07, 1, 2, 3, 4, 5, 6, 7]).is_err());
+    }
+
+    #[test]
+    fn invalid_r6_cbr_frames() {
+        assert!(Packet::from_slice(&[0x03, 0x02, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn invalid_r7_vbr_frames() {
+        assert!(Packet::from_slice(&[0x03, 0x82, 5, 1]).is_err());
+        assert!(Packet::from_slice(&[0x03, 0x83, 1, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn invalid_padding() {
+        // Unterminated padding length
+        assert!(Packet::from_slice(&[0x03, 0x41, 255, 255]).is_err());
+        // Padding past the end
+        assert!(Packet::from_slice(&[0x03, 0x41, 10, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn accessors() {
+        let p = Packet::from_slice(&[0x7b, 0x43, 2, 1, 2, 3, 4, 5, 6, 0, 0]).unwrap();
+
+        assert_eq!(p.config(), 15);
+        assert_eq!(p.code(), Code::Multiple);
+        assert_eq!(p.mode(), Mode::HYBRID);
+        assert_eq!(p.bandwidth(), Bandwidth::Full);
+        assert_eq!(p.frame_duration(), FrameDuration::Standard);
+        assert!(!p.is_vbr());
+        assert!(!p.is_stereo());
+        assert_eq!(p.padding(), 2);
+        assert_eq!(p.frames(), &[&[1, 2][..], &[3, 4][..], &[5, 6][..]][..]);
+        assert_eq!(p.frame_count(), 3);
+        assert_eq!(p.sample_count(), 2880);
+    }
+
+    #[test]
+    fn double_equal() {
+        let p = Packet::from_slice(&[0x09, 1, 2, 3, 4]).unwrap();
+
+        assert_eq!(p.frames, vec![&[1, 2][..], &[3, 4][..]]);
+    }
+
+    #[test]
+    fn multiple_vbr() {
+        let p = Packet::from_slice(&[0x0b, 0x83, 1, 2, 1, 2, 3, 4, 5]).unwrap();
+
+        assert_eq!(p.frames, vec![&[1][..], &[2, 3][..], &[4, 5][..]]);
+    }
+
+    #[test]
+    fn self_delimited() {
+        let buf = [
+            0x08, 3, 1, 2, 3, // code 0
+            0x09, 2, 1, 2, 3, 4, // code 1
+            0x0a, 1, 2, 1, 2, 3, // code 2
+            0x0b, 0x02, 2, 1, 2, 3, 4, // code 3, cbr
+            0x0b, 0xc3, 2, 1, 0, 2, 1, 2, 3, 0, 0, // code 3, vbr and padding
+            0x08, 1, 2, 3, // regular code 0
+        ];
+        let frames: &[&[&[u8]]] = &[
+            &[&[1, 2, 3]],
+            &[&[1, 2], &[3, 4]],
+            &[&[1], &[2, 3]],
+            &[&[1, 2], &[3, 4]],
+            &[&[1], &[], &[2, 3]],
+        ];
+
+        let mut b = &buf[..];
+        for f in frames {
+            let (p, consumed) = Packet::from_slice_self_delimited(b).unwrap();
+            assert_eq!(&p.frames[..], *f);
+            b = &b[consumed..];
+        }
+
+        let p = Packet::from_slice(b).unwrap();
+        assert_eq!(p.frames, vec![&[1, 2, 3][..]]);
+    }
+
+    #[test]
+    fn self_delimited_truncated() {
+        assert!(Packet::from_slice_self_delimited(&[0x08, 3, 1, 2]).is_err());
+        assert!(Packet::from_slice_self_delimited(&[0x0b, 0x83, 1, 2, 1]).is_err());
+        assert!(Packet::from_slice_self_delimited(&[]).is_err());
+    }
+
+    #[test]
+    fn write() {
+        let a = [1u8; 300];
+        let b = [2u8; 10];
+        let cases: &[(&[&[u8]], u8, usize)] = &[
+            (&[&a], 0, 301),
+            (&[&b, &b], 1, 21),
+            (&[&a, &b], 2, 313),
+            (&[&b, &b, &b], 3, 32),
+            (&[&b, &a, &b], 3, 325),
+        ];
+
+        for &(frames, code, size) in cases {
+            let mut p = Packet::from_slice(&[0x7c, 0]).unwrap();
+            p.frames = frames.to_vec();
+
+            let mut out = Vec::new();
+            assert_eq!(p.write(&mut out).unwrap(), size);
+            assert_eq!(out[0], 0x7c | code);
+            assert_eq!(Packet::from_slice(&out).unwrap().frames, frames);
+
+            out.clear();
+            p.write_self_delimited(&mut out).unwrap();
+            out.extend_from_slice(&[0x7c, 0]);
+
+            let (q, consumed) = Packet::from_slice_self_delimited(&out).unwrap();
+            assert_eq!(q.frames, frames);
+            assert_eq!(consumed, out.len() - 2);
+        }
+    }
+
+    #[test]
+    fn repacketize() {
+        let mut r = Repacketizer::new();
+
+        r.push(&[0x08, 1, 2]).unwrap();
+        r.push(&[0x09, 3, 4, 5, 6]).unwrap();
+        r.push(&[0x0a, 1, 7, 8, 9]).unwrap();
+
+        // Mismatching configuration and stereo flag
+        assert!(r.push(&[0x10, 1]).is_err());
+        assert!(r.push(&[0x0c, 1]).is_err());
+        assert_eq!(r.frame_count(), 5);
+
+        let mut out = Vec::new();
+        r.write(&mut out).unwrap();
+        assert_eq!(out, [0x0b, 0x85, 2, 2, 2, 1, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        out.clear();
+        r.write_range(1..3, &mut out).unwrap();
+        assert_eq!(out, [0x09, 3, 4, 5, 6]);
+
+        assert!(r.write_range(4..6, &mut out).is_err());
+
+        // 20ms frames, 6 of them at most
+        r.push(&[0x0b, 0x01, 0]).unwrap();
+        assert!(r.push(&[0x08, 0]).is_err());
+    }
+
+    #[test]
+    fn pad() {
+        let frames = [1u8, 2, 3, 4, 5, 6];
+        let p = Packet::from_slice(&[0x08, 1, 2, 3]).unwrap();
+
+        // Already the right size
+        assert_eq!(p.pad_to(4).unwrap(), [0x08, 1, 2, 3]);
+        assert!(p.pad_to(3).is_err());
+
+        // The padding lacing takes a byte more every 254 bytes
+        for &(len, padding) in &[(5, 0), (6, 0), (7, 1), (260, 254), (261, 254), (600, 592)] {
+            let buf = p.pad_to(len).unwrap();
+            assert_eq!(buf.len(), len);
+
+            let q = Packet::from_slice(&buf).unwrap();
+            assert_eq!(q.frames, p.frames);
+            assert_eq!(q.padding, padding);
+            assert_eq!(q.unpad().unwrap(), [0x08, 1, 2, 3]);
+        }
+
+        let p = Packet::from_slice(&[0x0a, 2, 1, 2, 3, 4, 5, 6]).unwrap();
+        let buf = p.pad_to(20).unwrap();
+        assert_eq!(&buf[..5], &[0x0b, 0xc2, 10, 2, 1]);
+        assert_eq!(Packet::from_slice(&buf).unwrap().frames, [&frames[..2], &frames[2..]]);
+    }
+
+    #[test]
+    fn pad_multistream() {
+        let buf = [0x08, 2, 1, 2, 0x0b, 0x41, 2, 3, 4, 0, 0];
+
+        let out = multistream_unpad(&buf, 2).unwrap();
+        assert_eq!(out, [0x08, 2, 1, 2, 0x08, 3, 4]);
+
+        let out = multistream_pad_to(&out, 16, 2).unwrap();
+        assert_eq!(out.len(), 16);
+        assert_eq!(&out[..4], &[0x08, 2, 1, 2]);
+        assert_eq!(Packet::from_slice(&out[4..]).unwrap().frames, [&[3u8, 4][..]]);
+
+        assert!(multistream_pad_to(&buf, 6, 2).is_err());
+        assert!(multistream_unpad(&buf, 3).is_err());
+    }
+
+    #[test]
+    fn extensions() {
+        let buf = [
+            0x0b, 0x42, 13, 7, 1, // two frames and 13 bytes of padding
+            0x05, 9, 0x03, 1, 0x14, 0x41, 3, 1, 2, 3, 0x01, 0, 0,
+        ];
+        let p = Packet::from_slice(&buf).unwrap();
+        assert_eq!(p.frames, [&[7u8][..], &[1]]);
+
+        let exts: Vec<_> = p.extensions().map(|e| e.unwrap()).collect();
+        assert_eq!(
+            exts,
+            [
+                Extension { id: 2, frame: 0, data: &[9] },
+                Extension { id: 10, frame: 1, data: &[] },
+                Extension { id: 32, frame: 1, data: &[1, 2, 3] },
+            ]
+        );
+
+        // No frame 2
+        let buf = [0x0b, 0x42, 3, 7, 1, 0x03, 2, 0];
+        let p = Packet::from_slice(&buf).unwrap();
+        let mut exts = p.extensions();
+        assert!(exts.next().unwrap().is_err());
+        assert!(exts.next().is_none());
+    }
+
+    #[test]
+    fn write_extensions() {
+        let long = [3u8; 300];
+        let exts = [
+            Extension { id: 64, frame: 3, data: &long },
+            Extension { id: 5, frame: 0, data: &[] },
+            Extension { id: 5, frame: 1, data: &[1] },
+        ];
+
+        let p = Packet::from_slice(&[0x0b, 4, 1, 2, 3, 4]).unwrap();
+        let mut out = Vec::new();
+        p.write_with_extensions(&exts, &mut out).unwrap();
+
+        let q = Packet::from_slice(&out).unwrap();
+        assert_eq!(q.frames, p.frames);
+
+        let parsed: Vec<_> = q.extensions().map(|e| e.unwrap()).collect();
+        assert_eq!(parsed, [exts[1], exts[2], exts[0]]);
+
+        // Padding after the extensions does not change them
+        let mut ext = Vec::new();
+        super::write_extensions(&exts, 4, &mut ext).unwrap();
+        let mut out = Vec::new();
+        p.write_framing(&mut out, false, Some(400), &ext).unwrap();
+
+        let q = Packet::from_slice(&out).unwrap();
+        assert_eq!(q.extensions().count(), 3);
+
+        let invalid = [
+            Extension { id: 1, frame: 0, data: &[] },
+            Extension { id: 5, frame: 0, data: &[1, 2] },
+            Extension { id: 64, frame: 4, data: &[] },
+        ];
+        for e in &invalid {
+            assert!(p.write_with_extensions(&[*e], &mut out).is_err());
+        }
     }
 }
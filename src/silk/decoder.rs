@@ -9,6 +9,7 @@ use crate::entropy::*;
 use crate::maths::*;
 use crate::packet::*;
 
+use crate::silk::resampler::Resampler;
 use crate::silk::tables::*;
 
 use std::ops::Range;
@@ -29,6 +30,7 @@ pub struct SilkInfo {
 #[derive(Debug)]
 pub struct Silk {
     stereo: bool,
+    prev_stereo: bool,
     stereo_out: bool,
     frames: usize,
     frame_len: usize,
@@ -40,6 +42,8 @@ pub struct Silk {
     // Todo use directly an AudioQueue ?
     pub left_outbuf: Vec<f32>,
     pub right_outbuf: Vec<f32>,
+
+    resampler: [Resampler; 2],
 }
 
 #[derive(Debug, Default)]
@@ -275,12 +279,12 @@ pub trait Band {
             let maxabs = ((maxabs.abs() + (1 << 4)) >> 5) as u32;
 
             if maxabs > 32767 {
-                let max = maxabs.max(163838);
+                let max = maxabs.min(163838);
                 let start = 65470 - ((max - 32767) << 14) / ((max * (k as u32 + 1)) >> 2);
                 let mut chirp = start;
 
                 for v in a.iter_mut() {
-                    *v = v.mul_round(chirp, 16);
+                    *v = v.mul_shift(chirp, 16);
                     chirp = ((start as u32 * chirp as u32 + 32768) >> 16) as u32;
                 }
             } else {
@@ -311,7 +315,7 @@ pub trait Band {
             let mut chirp = start;
 
             for (v, l) in a.iter_mut().zip(lpc.iter_mut()) {
-                *v = v.mul_round(chirp, 16);
+                *v = v.mul_shift(chirp, 16);
                 *l = ((*v + (1 << 4)) >> 5) as i16;
 
                 chirp = (start * chirp + 32768) >> 16;
@@ -691,6 +695,16 @@ const LTP_ORDER: usize = 5;
 const RES_HISTORY: usize = 288 + LTP_ORDER / 2;
 const LPC_HISTORY: usize = 322;
 
+// Packet loss concealment tuning, as in libopus
+const PLC_BWE: f32 = 0.99;
+const PLC_HARM_ATT: [f32; 2] = [0.99, 0.95];
+const PLC_RAND_ATT_V: [f32; 2] = [0.95, 0.8];
+const PLC_RAND_ATT_UV: [f32; 2] = [0.99, 0.9];
+const PLC_PITCH_DRIFT: f32 = 0.01;
+const PLC_LTP_GAIN_MIN: f32 = 0.7;
+const PLC_LTP_GAIN_MAX: f32 = 0.95;
+const PLC_RAND_BUF_SIZE: usize = 128;
+
 const LCG_SEED: &ICDFContext = &ICDFContext {
     total: 256,
     dist: &[64, 128, 192, 256],
@@ -1293,7 +1307,123 @@ const QUANT_OFFSET: &[&[i32]] = &[
     &[8, 25],  // Voiced
 ];
 
+/// State kept to extrapolate lost frames
+#[derive(Debug, Default, Clone)]
+struct Plc {
+    voiced: bool,
+    pitch_lag: f32,
+    ltp_taps: [f32; LTP_ORDER],
+    ltp_scale: f32,
+    gains: [f32; 2],
+    lpc: [f32; 16],
+    noise: Vec<f32>,
+    rand_scale: f32,
+    seed: u32,
+    loss_count: usize,
+    energy: f32,
+}
+
+/// Inverse of the prediction gain of a LPC filter
+fn inverse_prediction_gain(lpc: &[f32]) -> f32 {
+    let mut a = [0f32; 16];
+    let mut inv_gain = 1f32;
+
+    a[..lpc.len()].copy_from_slice(lpc);
+
+    for k in (0..lpc.len()).rev() {
+        let rc = a[k];
+        let rc2 = 1.0 - rc * rc;
+
+        if rc2 <= 0.0 {
+            return 0.0;
+        }
+
+        inv_gain *= rc2;
+
+        let prev = a;
+        for j in 0..k {
+            a[j] = (prev[j] + rc * prev[k - j - 1]) / rc2;
+        }
+    }
+
+    inv_gain
+}
+
+impl Plc {
+    /// Keep what is needed to conceal a loss right after this frame
+    fn update(
+        &mut self,
+        info: &SilkInfo,
+        sfs: &[SubFrame],
+        voiced: bool,
+        ltp_scale: f32,
+        lpc: &[f32],
+        innovation: &[f32],
+    ) {
+        let n = sfs.len();
+        let sf_size = info.sf_size;
+
+        self.voiced = voiced;
+        self.ltp_taps = [0f32; LTP_ORDER];
+        self.ltp_scale = ltp_scale;
+
+        if voiced {
+            // Use the strongest long-term predictor of the last pitch period
+            let last_lag = sfs[n - 1].pitch_lag as usize;
+            let mut ltp_gain = 0f32;
+
+            self.pitch_lag = last_lag as f32;
+
+            for (j, sf) in sfs.iter().rev().enumerate() {
+                if j * sf_size >= last_lag {
+                    break;
+                }
+                let gain: f32 = sf.ltp_taps.iter().sum();
+                if gain > ltp_gain {
+                    ltp_gain = gain;
+                    self.pitch_lag = sf.pitch_lag as f32;
+                }
+            }
+
+            self.ltp_taps[LTP_ORDER / 2] = ltp_gain.max(PLC_LTP_GAIN_MIN).min(PLC_LTP_GAIN_MAX);
+        } else {
+            self.pitch_lag = (18 * sf_size / 5) as f32;
+        }
+
+        self.lpc[..lpc.len()].copy_from_slice(lpc);
+        self.gains = [sfs[n - 2].gain, sfs[n - 1].gain];
+
+        // The noise comes from the quietest of the last two subframes
+        let energy = |k: usize| -> f32 {
+            let e: f32 = innovation[k * sf_size..(k + 1) * sf_size]
+                .iter()
+                .map(|v| v * v)
+                .sum();
+            e * sfs[k].gain * sfs[k].gain
+        };
+        let end = if energy(n - 2) < energy(n - 1) {
+            (n - 1) * sf_size
+        } else {
+            n * sf_size
+        };
+        let start = end.saturating_sub(PLC_RAND_BUF_SIZE);
+        let stop = (start + PLC_RAND_BUF_SIZE).min(innovation.len());
+
+        self.noise.clear();
+        self.noise.extend_from_slice(&innovation[start..stop]);
+    }
+}
+
+/// Per-packet VAD and LBRR flags
 #[derive(Debug, Default)]
+struct Header {
+    mid_vad: [bool; 3],
+    side_vad: [bool; 3],
+    mid_lbrr: [bool; 3],
+    side_lbrr: [bool; 3],
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct SilkFrame {
     frame_type: FrameType,
     log_gain: isize,
@@ -1306,6 +1436,8 @@ pub struct SilkFrame {
     interp_factor4: bool,
     previous_lag: i32,
 
+    plc: Plc,
+
     /* arrays are second class citizens
     output: [f32; LPC_HISTORY],
     lpc_history: [f32; LPC_HISTORY],
@@ -1652,6 +1784,7 @@ impl SilkFrame {
             self.interpolated = false;
             self.interp_factor4 = false;
             self.previous_lag = 0;
+            self.plc = Plc::default();
 
             self.output.clear();
             self.lpc_history.clear();
@@ -1771,6 +1904,8 @@ impl SilkFrame {
 
         // println!("residuals {:?}", &residuals);
 
+        let innovation = residuals[RES_HISTORY..RES_HISTORY + info.f_size].to_vec();
+
         // if self.mono_only { return Ok(()) }
         for i in 0..info.subframes {
             let sf = &sfs[i];
@@ -1886,6 +2021,28 @@ impl SilkFrame {
 
         self.prev_voiced = self.frame_type.voiced;
 
+        if self.plc.loss_count > 0 {
+            self.glue(info);
+        }
+
+        self.plc.update(
+            info,
+            &sfs[..info.subframes],
+            self.frame_type.voiced,
+            ltpscale,
+            &self.lpc[..order],
+            &innovation,
+        );
+        self.plc.loss_count = 0;
+
+        self.advance(info);
+
+        self.coded = true;
+
+        Ok(())
+    }
+
+    fn advance(&mut self, info: &SilkInfo) {
         //        println!("flength {}", info.f_size);
 
         for i in 0..LPC_HISTORY {
@@ -1896,10 +2053,136 @@ impl SilkFrame {
                 self.lpc_history[i], self.output[i]
             ); */
         }
+    }
 
-        self.coded = true;
+    /// Fade in the first frame after a loss if it is louder than the
+    /// concealed one
+    fn glue(&mut self, info: &SilkInfo) {
+        let out = &mut self.output[LPC_HISTORY..LPC_HISTORY + info.f_size];
+        let energy: f32 = out.iter().map(|v| v * v).sum();
+
+        if energy > self.plc.energy {
+            let mut gain = (self.plc.energy / energy).sqrt();
+            let slope = (1.0 - gain) / info.f_size as f32 * 4.0;
+
+            for v in out.iter_mut() {
+                *v *= gain;
+                gain += slope;
+                if gain > 1.0 {
+                    break;
+                }
+            }
+        }
+    }
 
-        Ok(())
+    /// Extrapolate a lost frame from the previous ones
+    ///
+    /// The long-term prediction and the noise excitation fade out at
+    /// every subframe while the pitch lag slowly drifts, as in libopus.
+    fn conceal(&mut self, info: &SilkInfo) {
+        // Nothing to extrapolate from, the frame is left silent
+        if !self.coded {
+            let range = LPC_HISTORY..LPC_HISTORY + info.f_size;
+            self.output[range.clone()].iter_mut().for_each(|v| *v = 0.0);
+            self.lpc_history[range].iter_mut().for_each(|v| *v = 0.0);
+            self.advance(info);
+            return;
+        }
+
+        let order = if info.bandwidth > Bandwidth::Medium {
+            WB::ORDER
+        } else {
+            NB_MB::ORDER
+        };
+        let max_lag = (18 * info.sf_size / 5) as f32;
+        let plc = &mut self.plc;
+        let loss = plc.loss_count.min(1);
+        let harm_gain = PLC_HARM_ATT[loss];
+        let mut rand_gain = if plc.voiced {
+            PLC_RAND_ATT_V[loss]
+        } else {
+            PLC_RAND_ATT_UV[loss]
+        };
+
+        if plc.loss_count == 0 {
+            if plc.voiced {
+                let ltp_gain: f32 = plc.ltp_taps.iter().sum();
+                plc.rand_scale = (1.0 - ltp_gain).max(0.2) * plc.ltp_scale;
+            } else {
+                // Less noise through a high gain filter
+                let inv_gain = inverse_prediction_gain(&plc.lpc[..order]);
+                plc.rand_scale = 1.0;
+                rand_gain *= inv_gain.min(1.0 / 8.0).max(1.0 / 256.0) * 8.0;
+            }
+        }
+
+        let mut lpc = [0f32; 16];
+        let mut chirp = 1f32;
+        for (a, &c) in lpc[..order].iter_mut().zip(plc.lpc.iter()) {
+            chirp *= PLC_BWE;
+            *a = c * chirp;
+        }
+        let lpc = &lpc[..order];
+        let gain = plc.gains[1];
+
+        // Re-whiten the past output to feed the long-term predictor
+        let hist = LPC_HISTORY - order;
+        let mut exc = vec![0f32; hist + info.f_size];
+
+        for (i, e) in exc[..hist].iter_mut().enumerate() {
+            let n = order + i;
+            let mut sum = self.output[n];
+            for (k, &c) in lpc.iter().enumerate() {
+                sum -= c * self.output[n - k - 1];
+            }
+            *e = sum / gain;
+        }
+
+        // The taps must reach back to already computed excitation
+        let min_lag = LTP_ORDER / 2 + 1;
+        let mut lag = (plc.pitch_lag.round() as usize).max(min_lag);
+        let mut pos = hist;
+        for _ in 0..info.subframes {
+            for _ in 0..info.sf_size {
+                let mut sum = 0f32;
+                for (j, &b) in plc.ltp_taps.iter().enumerate() {
+                    sum += b * exc[pos + LTP_ORDER / 2 - lag - j];
+                }
+
+                plc.seed = plc.seed.wrapping_mul(196314165).wrapping_add(907633515);
+                if !plc.noise.is_empty() {
+                    let idx = (plc.seed >> 25) as usize % plc.noise.len();
+                    sum += plc.noise[idx] * plc.rand_scale;
+                }
+
+                exc[pos] = sum;
+                pos += 1;
+            }
+
+            for b in plc.ltp_taps.iter_mut() {
+                *b *= harm_gain;
+            }
+            plc.rand_scale *= rand_gain;
+            plc.pitch_lag = (plc.pitch_lag * (1.0 + PLC_PITCH_DRIFT)).min(max_lag);
+            lag = (plc.pitch_lag.round() as usize).max(min_lag);
+        }
+
+        let mut energy = 0f32;
+        for (i, &e) in exc[hist..].iter().enumerate() {
+            let n = LPC_HISTORY + i;
+            let mut sum = e * gain;
+            for (k, &c) in lpc.iter().enumerate() {
+                sum += c * self.lpc_history[n - k - 1];
+            }
+            self.lpc_history[n] = sum;
+            self.output[n] = sum.max(-1f32).min(1f32);
+            energy += self.output[n] * self.output[n];
+        }
+
+        plc.energy = energy;
+        plc.loss_count += 1;
+
+        self.advance(info);
     }
 }
 
@@ -1907,6 +2190,7 @@ impl Silk {
     pub fn new(stereo_out: bool) -> Self {
         Silk {
             stereo: true,
+            prev_stereo: false,
             stereo_out: stereo_out,
             frames: 0,
             frame_len: 0,
@@ -1928,6 +2212,8 @@ impl Silk {
             side_frame: SilkFrame::new(),
             left_outbuf: vec![0f32; 960],
             right_outbuf: vec![0f32; 960],
+
+            resampler: [Resampler::new(16000), Resampler::new(16000)],
         }
     }
 
@@ -1937,10 +2223,32 @@ impl Silk {
 
         self.info.prev0 = 0.0;
         self.info.prev1 = 0.0;
+
+        self.prev_stereo = false;
+        let rate = self.resampler[0].rate();
+        self.resampler = [Resampler::new(rate), Resampler::new(rate)];
     }
 
     pub fn setup(&mut self, pkt: &Packet) {
-        match pkt.frame_duration {
+        self.stereo = pkt.stereo;
+        self.info.bandwidth = pkt.bandwidth.min(Bandwidth::Wide);
+        self.info.sf_size = match self.info.bandwidth {
+            Bandwidth::Narrow => 40,
+            Bandwidth::Medium => 60,
+            Bandwidth::Wide => 80,
+            _ => unreachable!(),
+        };
+
+        let rate = self.info.bandwidth as usize;
+        if self.resampler[0].rate() != rate {
+            self.resampler = [Resampler::new(rate), Resampler::new(rate)];
+        }
+
+        self.set_duration(pkt.frame_duration);
+    }
+
+    fn set_duration(&mut self, duration: FrameDuration) {
+        match duration {
             FrameDuration::Medium => {
                 self.frames = 1;
                 self.info.subframes = 2;
@@ -1959,14 +2267,6 @@ impl Silk {
             }
             _ => unreachable!(),
         }
-        self.stereo = pkt.stereo;
-        self.info.bandwidth = pkt.bandwidth.min(Bandwidth::Wide);
-        self.info.sf_size = match self.info.bandwidth {
-            Bandwidth::Narrow => 40,
-            Bandwidth::Medium => 60,
-            Bandwidth::Wide => 80,
-            _ => unreachable!(),
-        };
         self.info.f_size = self.info.sf_size * self.info.subframes;
 
         // TODO: avoid the memset
@@ -2060,57 +2360,154 @@ impl Silk {
         self.info.prev1 = self.info.weight1;
     }
 
-    pub fn decode(&mut self, rd: &mut RangeDecoder) -> Result<usize> {
-        let mut mid_vad = [false; 3];
-        let mut side_vad = [false; 3];
-        fn lp(rd: &mut RangeDecoder, vad: &mut [bool]) -> Result<()> {
+    fn parse_lbrr_flags(&self, rd: &mut RangeDecoder, lbrr: &mut [bool]) {
+        let flags = match self.frames {
+            1 => 1,
+            2 => rd.decode_icdf(LBRR_FLAGS_40MS),
+            _ => rd.decode_icdf(LBRR_FLAGS_60MS),
+        };
+
+        for (i, f) in lbrr.iter_mut().enumerate() {
+            *f = (flags >> i) & 1 != 0;
+        }
+    }
+
+    /// Parse the LBRR frames without altering the decoder state
+    ///
+    /// See [section-4.2.4](https://tools.ietf.org/html/rfc6716#section-4.2.4)
+    fn skip_lbrr(
+        &mut self,
+        rd: &mut RangeDecoder,
+        mid_lbrr: &[bool],
+        side_lbrr: &[bool],
+    ) -> Result<()> {
+        let mut mid_frame = self.mid_frame.clone();
+        let mut side_frame = self.side_frame.clone();
+
+        for i in 0..self.frames {
+            if mid_lbrr[i] {
+                if self.stereo {
+                    self.parse_stereo_weight(rd, side_lbrr[i]);
+                }
+                let first = i == 0 || !mid_lbrr[i - 1];
+                mid_frame.parse(rd, &self.info, true, first)?;
+            }
+
+            if side_lbrr[i] {
+                let first = i == 0 || !side_lbrr[i - 1];
+                side_frame.parse(rd, &self.info, true, first)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the VAD and LBRR flags
+    ///
+    /// See [section-4.2.3](https://tools.ietf.org/html/rfc6716#section-4.2.3)
+    fn parse_header(&mut self, rd: &mut RangeDecoder) -> Header {
+        let mut h = Header::default();
+        fn lp(rd: &mut RangeDecoder, vad: &mut [bool]) -> bool {
             for v in vad {
                 *v = rd.decode_logp(1);
             }
-            if rd.decode_logp(1) {
-                return Err(Error::Unsupported("LBRR frames".to_owned()));
-            } else {
-                Ok(())
-            }
+            rd.decode_logp(1)
         }
 
-        lp(rd, &mut mid_vad[..self.frames])?;
+        // On a mono to stereo switch the side channel starts from a
+        // clean state and the second output channel picks up from the first
+        if self.stereo && !self.prev_stereo {
+            self.side_frame.flush();
+            self.info.prev0 = 0.0;
+            self.info.prev1 = 0.0;
+            self.resampler[1] = self.resampler[0].clone();
+        }
+
+        let mid_has_lbrr = lp(rd, &mut h.mid_vad[..self.frames]);
+        let side_has_lbrr = self.stereo && lp(rd, &mut h.side_vad[..self.frames]);
 
-        if self.stereo {
-            lp(rd, &mut side_vad[..self.frames])?;
+        if mid_has_lbrr {
+            self.parse_lbrr_flags(rd, &mut h.mid_lbrr[..self.frames]);
+        }
+        if side_has_lbrr {
+            self.parse_lbrr_flags(rd, &mut h.side_lbrr[..self.frames]);
         }
-        //        println!("{:?} {:?}", mid_vad, side_vad);
-        for i in 0..self.frames {
-            let first = i == 0;
-            let midonly = if self.stereo {
-                self.parse_stereo_weight(rd, side_vad[i])
-            } else {
-                false
-            };
-            //            println!("{} midonly {} stereo {}", i, midonly, self.stereo);
-            self.mid_frame.parse(rd, &self.info, mid_vad[i], first)?;
 
-            if self.stereo && !midonly {
-                self.side_frame.parse(rd, &self.info, side_vad[i], first)?;
-            }
+        h
+    }
 
-            if midonly {
-                self.side_frame.flush();
-            }
-            let out_range = i * self.info.f_size..(i + 1) * self.info.f_size;
-            if self.stereo && self.stereo_out {
-                // println!("unmix");
-                self.unmix_ms(out_range);
+    fn decode_frame(
+        &mut self,
+        rd: &mut RangeDecoder,
+        i: usize,
+        (mid_vad, mid_first): (bool, bool),
+        (side_vad, side_first): (bool, bool),
+        lbrr: bool,
+    ) -> Result<()> {
+        let midonly = if self.stereo {
+            self.parse_stereo_weight(rd, side_vad)
+        } else {
+            false
+        };
+        //            println!("{} midonly {} stereo {}", i, midonly, self.stereo);
+        self.mid_frame.parse(rd, &self.info, mid_vad, mid_first)?;
+
+        if self.stereo && !midonly {
+            // A LBRR side frame is present only if flagged, the missing
+            // one is concealed
+            if lbrr && !side_vad {
+                self.side_frame.conceal(&self.info);
             } else {
-                let in_start = LPC_HISTORY - self.info.f_size - 2;
-                let in_range = in_start..in_start + self.info.f_size;
-                let inbuf = &self.mid_frame.output[in_range];
+                self.side_frame.parse(rd, &self.info, side_vad, side_first)?;
+            }
+        }
 
-                if self.stereo_out {
-                    self.left_outbuf[out_range.clone()].copy_from_slice(inbuf);
-                }
-                self.right_outbuf[out_range].copy_from_slice(inbuf);
+        if midonly {
+            self.side_frame.flush();
+        }
+
+        self.store_frame(i);
+
+        Ok(())
+    }
+
+    fn store_frame(&mut self, i: usize) {
+        let out_range = i * self.info.f_size..(i + 1) * self.info.f_size;
+        if self.stereo && self.stereo_out {
+            // println!("unmix");
+            self.unmix_ms(out_range);
+        } else {
+            let in_start = LPC_HISTORY - self.info.f_size - 1;
+            let in_range = in_start..in_start + self.info.f_size;
+            let inbuf = &self.mid_frame.output[in_range];
+
+            if self.stereo_out {
+                self.left_outbuf[out_range.clone()].copy_from_slice(inbuf);
             }
+            self.right_outbuf[out_range].copy_from_slice(inbuf);
+        }
+    }
+
+    fn conceal_frame(&mut self, i: usize) {
+        self.mid_frame.conceal(&self.info);
+        if self.stereo && self.side_frame.coded {
+            self.side_frame.conceal(&self.info);
+        }
+
+        self.store_frame(i);
+    }
+
+    pub fn decode(&mut self, rd: &mut RangeDecoder) -> Result<usize> {
+        let h = self.parse_header(rd);
+
+        if h.mid_lbrr.iter().chain(h.side_lbrr.iter()).any(|&f| f) {
+            self.skip_lbrr(rd, &h.mid_lbrr, &h.side_lbrr)?;
+        }
+
+        //        println!("{:?} {:?}", mid_vad, side_vad);
+        for i in 0..self.frames {
+            let first = i == 0;
+            self.decode_frame(rd, i, (h.mid_vad[i], first), (h.side_vad[i], first), false)?;
         }
 
 /*        println!("stereo {} out {}", self.stereo, self.stereo_out);
@@ -2125,4 +2522,84 @@ impl Silk {
 */
         Ok(0)
     }
+
+    /// Decode the LBRR frames in place of the frames of the previous,
+    /// lost, packet
+    ///
+    /// The frames without redundant data are concealed.
+    pub fn decode_fec(&mut self, rd: &mut RangeDecoder) -> Result<usize> {
+        let h = self.parse_header(rd);
+
+        for i in 0..self.frames {
+            let side_first = i == 0 || !h.side_lbrr[i - 1];
+
+            if h.mid_lbrr[i] {
+                let mid_first = i == 0 || !h.mid_lbrr[i - 1];
+                let side = (h.side_lbrr[i], side_first);
+                self.decode_frame(rd, i, (true, mid_first), side, true)?;
+            } else {
+                // A side frame without its mid one is skipped, the
+                // following LBRR frames start after it
+                if h.side_lbrr[i] {
+                    let mut side_frame = self.side_frame.clone();
+                    side_frame.parse(rd, &self.info, true, side_first)?;
+                }
+                self.conceal_frame(i);
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Conceal a lost packet of `duration` extrapolating from the
+    /// previous frames
+    pub fn conceal(&mut self, duration: FrameDuration) {
+        self.set_duration(duration);
+
+        for i in 0..self.frames {
+            self.conceal_frame(i);
+        }
+    }
+
+    /// Upsample the decoded frames to 48kHz and add them to the
+    /// interleaved `out`
+    pub fn output(&mut self, out: &mut [f32], channels: usize) {
+        fn to_i16(out: &mut [i16], inp: &[f32]) {
+            for (o, &v) in out.iter_mut().zip(inp.iter()) {
+                *o = (v * 32768.0).round().max(-32768.0).min(32767.0) as i16;
+            }
+        }
+
+        let len = self.frames * self.info.f_size;
+        let mut inbuf = [0i16; 960];
+        let mut outbuf = [[0i16; 960 * 3]; 2];
+
+        // The right buffer holds the first channel
+        to_i16(&mut inbuf, &self.right_outbuf[..len]);
+        let n = self.resampler[0].process(&mut outbuf[0], &inbuf[..len]);
+
+        if channels > 1 {
+            let (out0, out1) = outbuf.split_at_mut(1);
+            if self.stereo {
+                to_i16(&mut inbuf, &self.left_outbuf[..len]);
+                self.resampler[1].process(&mut out1[0], &inbuf[..len]);
+            } else {
+                out1[0] = out0[0];
+                // Keep the second channel resampler in step for the
+                // first frame after a stereo to mono switch
+                if self.prev_stereo {
+                    let f_size = self.info.f_size;
+                    self.resampler[1].process(&mut out1[0], &inbuf[..f_size]);
+                }
+            }
+        }
+
+        for (c, buf) in outbuf.iter().enumerate().take(channels) {
+            for (o, &v) in out[c..].iter_mut().step_by(channels).zip(buf[..n].iter()) {
+                *o += v as f32 / 32768.0;
+            }
+        }
+
+        self.prev_stereo = self.stereo;
+    }
 }
@@ -1,5 +1,6 @@
 mod tables;
 mod decoder;
+mod resampler;
 #[cfg(test)]
 mod tests;
 
@@ -0,0 +1,263 @@
+//!
+//! Silk output resampler
+//!
+//! Bit-exact port of the libopus decoder-side upsampler: a 2x all-pass
+//! IIR upsampler followed by a fractional 8-tap FIR interpolator.
+//!
+
+const ORDER_FIR: usize = 8;
+const MAX_BATCH_SIZE_MS: usize = 10;
+
+const UP2_HQ_0: [i32; 3] = [1746, 14986, 39083 - 65536];
+const UP2_HQ_1: [i32; 3] = [6854, 25769, 55542 - 65536];
+
+const FRAC_FIR_12: [[i32; ORDER_FIR / 2]; 12] = [
+    [189, -600, 617, 30567],
+    [117, -159, -1070, 29704],
+    [52, 221, -2392, 28276],
+    [-4, 529, -3350, 26341],
+    [-48, 758, -3956, 23973],
+    [-80, 905, -4235, 21254],
+    [-99, 972, -4222, 18278],
+    [-107, 967, -3957, 15143],
+    [-103, 896, -3487, 11950],
+    [-91, 773, -2865, 8798],
+    [-71, 611, -2143, 5784],
+    [-46, 425, -1375, 2996],
+];
+
+const OUT_RATE: i32 = 48000;
+
+#[inline(always)]
+fn smulwb(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i16 as i64) >> 16) as i32
+}
+
+#[inline(always)]
+fn sat16(v: i32) -> i16 {
+    v.max(i16::MIN as i32).min(i16::MAX as i32) as i16
+}
+
+#[inline(always)]
+fn rshift_round(v: i32, shift: usize) -> i32 {
+    ((v >> (shift - 1)) + 1) >> 1
+}
+
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    iir: [i32; 6],
+    fir: [i16; ORDER_FIR],
+    delay_buf: [i16; 16],
+
+    in_khz: usize,
+    delay: usize,
+    batch_size: usize,
+    inv_ratio: i32,
+}
+
+impl Resampler {
+    /// Upsample from `rate` (8, 12 or 16kHz) to 48kHz
+    pub fn new(rate: usize) -> Self {
+        let delay = match rate {
+            8000 => 0,
+            12000 => 4,
+            16000 => 7,
+            _ => unreachable!(),
+        };
+        let in_khz = rate / 1000;
+        let rate = rate as i32;
+
+        // Ratio of input/output samples in Q16, rounded up
+        let mut inv_ratio = ((rate << 15) / OUT_RATE) << 2;
+        while ((inv_ratio as i64 * OUT_RATE as i64) >> 16) < (rate << 1) as i64 {
+            inv_ratio += 1;
+        }
+
+        Resampler {
+            iir: [0; 6],
+            fir: [0; ORDER_FIR],
+            delay_buf: [0; 16],
+
+            in_khz,
+            delay,
+            batch_size: in_khz * MAX_BATCH_SIZE_MS,
+            inv_ratio,
+        }
+    }
+
+    pub fn rate(&self) -> usize {
+        self.in_khz * 1000
+    }
+
+    fn up2_hq(&mut self, out: &mut [i16], inp: &[i16]) {
+        let s = &mut self.iir;
+
+        for (o, &v) in out.chunks_exact_mut(2).zip(inp.iter()) {
+            let v = (v as i32) << 10;
+
+            // Even output sample
+            let y = v - s[0];
+            let x = smulwb(y, UP2_HQ_0[0]);
+            let out1 = s[0] + x;
+            s[0] = v + x;
+
+            let y = out1 - s[1];
+            let x = smulwb(y, UP2_HQ_0[1]);
+            let out2 = s[1] + x;
+            s[1] = out1 + x;
+
+            let y = out2 - s[2];
+            let x = y + smulwb(y, UP2_HQ_0[2]);
+            let out1 = s[2] + x;
+            s[2] = out2 + x;
+
+            o[0] = sat16(rshift_round(out1, 10));
+
+            // Odd output sample
+            let y = v - s[3];
+            let x = smulwb(y, UP2_HQ_1[0]);
+            let out1 = s[3] + x;
+            s[3] = v + x;
+
+            let y = out1 - s[4];
+            let x = smulwb(y, UP2_HQ_1[1]);
+            let out2 = s[4] + x;
+            s[4] = out1 + x;
+
+            let y = out2 - s[5];
+            let x = y + smulwb(y, UP2_HQ_1[2]);
+            let out1 = s[5] + x;
+            s[5] = out2 + x;
+
+            o[1] = sat16(rshift_round(out1, 10));
+        }
+    }
+
+    fn interpolate(&self, out: &mut [i16], buf: &[i16], max_index: i32) -> usize {
+        let mut index = 0;
+        let mut o = 0;
+
+        while index < max_index {
+            let table_index = smulwb(index & 0xFFFF, 12) as usize;
+            let b = &buf[(index >> 16) as usize..];
+            let c0 = &FRAC_FIR_12[table_index];
+            let c1 = &FRAC_FIR_12[11 - table_index];
+
+            let res = b[0] as i32 * c0[0]
+                + b[1] as i32 * c0[1]
+                + b[2] as i32 * c0[2]
+                + b[3] as i32 * c0[3]
+                + b[4] as i32 * c1[3]
+                + b[5] as i32 * c1[2]
+                + b[6] as i32 * c1[1]
+                + b[7] as i32 * c1[0];
+
+            out[o] = sat16(rshift_round(res, 15));
+            o += 1;
+            index += self.inv_ratio;
+        }
+
+        o
+    }
+
+    fn iir_fir(&mut self, out: &mut [i16], mut inp: &[i16]) -> usize {
+        let mut buf = [0i16; 2 * 16 * MAX_BATCH_SIZE_MS + ORDER_FIR];
+        let mut o = 0;
+
+        buf[..ORDER_FIR].copy_from_slice(&self.fir);
+
+        loop {
+            let n = inp.len().min(self.batch_size);
+
+            self.up2_hq(&mut buf[ORDER_FIR..ORDER_FIR + 2 * n], &inp[..n]);
+
+            o += self.interpolate(&mut out[o..], &buf, (n as i32) << 17);
+
+            inp = &inp[n..];
+
+            if inp.is_empty() {
+                self.fir.copy_from_slice(&buf[2 * n..2 * n + ORDER_FIR]);
+                break;
+            }
+
+            buf.copy_within(2 * n..2 * n + ORDER_FIR, 0);
+        }
+
+        o
+    }
+
+    /// Resample `inp`, the input must be at least 1ms long
+    ///
+    /// Returns the number of samples written to `out`.
+    pub fn process(&mut self, out: &mut [i16], inp: &[i16]) -> usize {
+        assert!(inp.len() >= self.in_khz);
+
+        let delay = self.delay;
+        let n = self.in_khz - delay;
+
+        self.delay_buf[delay..self.in_khz].copy_from_slice(&inp[..n]);
+
+        let delay_buf = self.delay_buf;
+        let o = self.iir_fir(out, &delay_buf[..self.in_khz]);
+        let o = o + self.iir_fir(&mut out[o..], &inp[n..inp.len() - delay]);
+
+        self.delay_buf[..delay].copy_from_slice(&inp[inp.len() - delay..]);
+
+        o
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Second 10ms frame of a sawtooth-like signal, checked against libopus
+    fn upsample(rate: usize, reference: &[i16]) {
+        let mut r = Resampler::new(rate);
+        let n = rate / 100;
+        let mut inp = [0i16; 160];
+        let mut out = [0i16; 480];
+
+        for f in 0..2 {
+            for (i, v) in inp[..n].iter_mut().enumerate() {
+                *v = (((i + f * n) * 1237) % 4001) as i16 - 2000;
+            }
+            assert_eq!(r.process(&mut out, &inp[..n]), 480);
+        }
+
+        assert_eq!(&out[..reference.len()], reference);
+    }
+
+    #[test]
+    fn upsample_8k() {
+        upsample(
+            8000,
+            &[
+                -1759, -1466, -980, -380, 242, 798, 1215, 1447, 1477, 1313, 991, 557, 68, -424,
+                -872, -1236, -1488, -1611, -1597, -1446, -1171, -796, -354, 107,
+            ],
+        );
+    }
+
+    #[test]
+    fn upsample_12k() {
+        upsample(
+            12000,
+            &[
+                352, 956, 1314, 1416, 1322, 1129, 920, 726, 520, 250, -111, -517, -842, -927, -653,
+                -28, 787, 1514, 1860, 1645, 890, -179, -1204, -1839,
+            ],
+        );
+    }
+
+    #[test]
+    fn upsample_16k() {
+        upsample(
+            16000,
+            &[
+                209, -957, -1878, -2077, -1460, -350, 734, 1397, 1562, 1426, 1238, 1085, 859, 415,
+                -207, -703, -685, 9, 1096, 1947, 1975, 1056, -340, -1423,
+            ],
+        );
+    }
+}
@@ -33,6 +33,16 @@ pub const FRAME_TYPE_ACTIVE: &ICDFContext = &ICDFContext {
     dist: &[24, 98, 246, 256],
 };
 
+pub const LBRR_FLAGS_40MS: &ICDFContext = &ICDFContext {
+    total: 256,
+    dist: &[0, 53, 106, 256],
+};
+
+pub const LBRR_FLAGS_60MS: &ICDFContext = &ICDFContext {
+    total: 256,
+    dist: &[0, 41, 61, 90, 131, 146, 174, 256],
+};
+
 pub const MSB_SUBFRAME_GAIN: &[&ICDFContext; 3] = &[
     &ICDFContext {
         total: 256,
@@ -29,7 +29,6 @@ use crate::packet::Packet;
         ];
 
         let outbuf = vec![
-            0.0,
             0.0,
             0.000018067658,
             0.000040303756,
@@ -509,6 +508,7 @@ use crate::packet::Packet;
             -0.00027067948,
             -0.00030915916,
             -0.00029481357,
+            -0.0002873543,
         ];
 
         decode(in_slice, true, &outbuf, &outbuf);
@@ -869,3 +869,84 @@ use crate::packet::Packet;
 
         assert_eq!(lpc, reference);
     }
+
+    // 20ms stereo packet carrying LBRR frames
+    const LBRR_PACKET: &[u8] = &[
+        76, 72, 1, 188, 115, 165, 182, 252, 235, 14, 220, 67, 116, 144, 37, 120, 224, 173,
+        109, 51, 56, 144, 211, 35, 212, 176, 132, 47, 39, 225, 26, 62, 151, 90, 0, 45, 94,
+        161, 163, 11, 250, 28, 196, 141, 39, 21, 36,
+    ];
+
+    #[test]
+    fn decode_lbrr() {
+        let p = Packet::from_slice(LBRR_PACKET).unwrap();
+
+        let mut silk = Silk::new(true);
+
+        silk.setup(&p);
+
+        for frame in p.frames {
+            let mut rd = RangeDecoder::new(frame);
+
+            silk.decode(&mut rd).unwrap();
+
+            assert!(rd.tell() <= frame.len() * 8);
+        }
+    }
+
+    #[test]
+    fn decode_lbrr_fec() {
+        let p = Packet::from_slice(LBRR_PACKET).unwrap();
+
+        let mut silk = Silk::new(true);
+
+        silk.setup(&p);
+
+        let mut rd = RangeDecoder::new(p.frames[0]);
+
+        silk.decode_fec(&mut rd).unwrap();
+
+        assert!(rd.tell() <= p.frames[0].len() * 8);
+        assert!(silk.right_outbuf.iter().any(|&v| v != 0.0));
+        assert!(silk.left_outbuf.iter().any(|&v| v != 0.0));
+    }
+
+    #[test]
+    fn conceal() {
+        let p = Packet::from_slice(LBRR_PACKET).unwrap();
+
+        let mut silk = Silk::new(true);
+
+        silk.setup(&p);
+
+        let mut rd = RangeDecoder::new(p.frames[0]);
+
+        silk.decode(&mut rd).unwrap();
+        silk.conceal(p.frame_duration);
+
+        assert!(silk.right_outbuf.iter().all(|v| v.is_finite()));
+        assert!(silk.right_outbuf.iter().any(|&v| v != 0.0));
+        assert!(silk.left_outbuf.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn conceal_first() {
+        let p = Packet::from_slice(LBRR_PACKET).unwrap();
+
+        let mut silk = Silk::new(true);
+
+        silk.setup(&p);
+
+        // Nothing decoded yet, the frames are left silent
+        silk.conceal(p.frame_duration);
+
+        assert!(silk.right_outbuf.iter().all(|&v| v == 0.0));
+        assert!(silk.left_outbuf.iter().all(|&v| v == 0.0));
+
+        // The frames without LBRR data are concealed the same way
+        let mut rd = RangeDecoder::new(p.frames[0]);
+        silk.decode_fec(&mut rd).unwrap();
+
+        assert!(silk.right_outbuf.iter().all(|v| v.is_finite()));
+        assert!(silk.left_outbuf.iter().all(|v| v.is_finite()));
+    }